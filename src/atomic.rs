@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+
+use crate::index::ResourceIndex;
+
+/// Lock-free holder for an index which is replaced as a whole,
+/// e.g. when re-indexing happens in the background. Readers never
+/// observe a partially built or partially updated index.
+#[derive(Debug)]
+pub struct AtomicResourceIndex {
+    current: ArcSwap<ResourceIndex>,
+}
+
+impl AtomicResourceIndex {
+    pub fn new(index: ResourceIndex) -> Self {
+        AtomicResourceIndex {
+            current: ArcSwap::from_pointee(index),
+        }
+    }
+
+    /// The index at the moment of the call, kept alive
+    /// for as long as the caller holds it.
+    pub fn load(&self) -> Arc<ResourceIndex> {
+        self.current.load_full()
+    }
+
+    /// Replaces the index for all subsequent `load` calls,
+    /// returning the previous one.
+    pub fn store(&self, index: ResourceIndex) -> Arc<ResourceIndex> {
+        self.current.swap(Arc::new(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    fn fixture(files: usize) -> TempDir {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        for i in 0..files {
+            fs::write(root.path().join(format!("{}.txt", i)), i.to_string())
+                .unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn readers_see_complete_index_during_swap() {
+        let small = fixture(2);
+        let large = fixture(5);
+        let small_root = small.path().canonicalize().unwrap();
+        let large_root = large.path().canonicalize().unwrap();
+
+        let atomic = Arc::new(AtomicResourceIndex::new(
+            ResourceIndex::build(&small_root).unwrap(),
+        ));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let atomic = atomic.clone();
+                let small_root = small_root.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let index = atomic.load();
+                        let expected = if index.root() == small_root {
+                            2
+                        } else {
+                            5
+                        };
+                        assert_eq!(index.size(), expected);
+                        assert!(index
+                            .path2meta
+                            .keys()
+                            .all(|path| path.starts_with(index.root())));
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..50 {
+            let root = if i % 2 == 0 {
+                &large_root
+            } else {
+                &small_root
+            };
+            atomic.store(ResourceIndex::build(root).unwrap());
+        }
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
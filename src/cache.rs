@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+
+use crate::id::ResourceId;
+use crate::meta::{Digest, FileNode, ResourceMeta};
+
+/// Ids of files hashed by previous builds, by device and inode.
+/// An entry is reused only while the file keeps its size
+/// and modification time, see `ResourceIndex::build_with_cache`.
+#[derive(Debug, Default)]
+pub(crate) struct ChecksumCache {
+    entries: HashMap<FileNode, CacheEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    modified: SystemTime,
+    id: ResourceId,
+    #[serde(default)]
+    text: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    minhash: Option<Vec<u64>>,
+}
+
+impl ChecksumCache {
+    /// Reads the cache, a missing file gives an empty one.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self::default())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let entries: Vec<(FileNode, CacheEntry)> =
+            serde_json::from_reader(BufReader::new(file))?;
+        Ok(ChecksumCache {
+            entries: entries.into_iter().collect(),
+        })
+    }
+
+    pub fn store(&self, path: &Path) -> Result<(), Error> {
+        let entries: Vec<(&FileNode, &CacheEntry)> =
+            self.entries.iter().collect();
+        let mut writer = BufWriter::new(fs::File::create(path)?);
+        serde_json::to_writer(&mut writer, &entries)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// What was learned by reading the file, if it is unchanged
+    /// since cached.
+    pub fn get(
+        &self,
+        node: FileNode,
+        size: u64,
+        modified: SystemTime,
+    ) -> Option<Digest> {
+        let entry = self.entries.get(&node)?;
+        if entry.size != size || entry.modified != modified {
+            return None;
+        }
+        Some((entry.id.clone(), entry.text, entry.minhash.clone()))
+    }
+
+    /// Remembers the files, replacing stale entries of their nodes.
+    pub fn extend<'a, I>(&mut self, metas: I)
+    where
+        I: IntoIterator<Item = &'a ResourceMeta>,
+    {
+        for meta in metas {
+            if let Some(node) = meta.inode {
+                let entry = CacheEntry {
+                    size: meta.size,
+                    modified: meta.modified,
+                    id: meta.id.clone(),
+                    text: meta.text,
+                    minhash: meta.minhash.clone(),
+                };
+                self.entries.insert(node, entry);
+            }
+        }
+    }
+}
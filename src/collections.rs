@@ -0,0 +1,137 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Error;
+
+use crate::id::ResourceId;
+use crate::index::{IndexUpdate, ResourceIndex};
+
+/// Several named indexes, queried and updated separately,
+/// but sharing one content-id space. Allows to find out which
+/// collections contain some resource without merging them.
+#[derive(Debug, Default)]
+pub struct ResourceCollections {
+    indexes: HashMap<String, ResourceIndex>,
+    locations: HashMap<ResourceId, HashSet<String>>,
+}
+
+impl ResourceCollections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the index under the name, replacing
+    /// and returning the previous index with this name.
+    pub fn insert<S: Into<String>>(
+        &mut self,
+        name: S,
+        index: ResourceIndex,
+    ) -> Option<ResourceIndex> {
+        let name = name.into();
+        let previous = self.remove(&name);
+
+        for id in index.ids() {
+            self.locations
+                .entry(id.clone())
+                .or_default()
+                .insert(name.clone());
+        }
+        self.indexes.insert(name, index);
+
+        previous
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<ResourceIndex> {
+        let index = self.indexes.remove(name)?;
+        for id in index.ids() {
+            self.forget_location(id, name);
+        }
+        Some(index)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ResourceIndex> {
+        self.indexes.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.indexes.keys().map(String::as_str)
+    }
+
+    /// Updates only the named collection.
+    pub fn update(&mut self, name: &str) -> Result<IndexUpdate, Error> {
+        let index = self.indexes.get_mut(name).ok_or_else(|| {
+            Error::msg(format!("Unknown collection {}", name))
+        })?;
+        let update = index.update()?;
+
+        for id in update.deleted.iter() {
+            self.forget_location(id, name);
+        }
+        for meta in update.added.values() {
+            self.locations
+                .entry(meta.id.clone())
+                .or_default()
+                .insert(name.to_owned());
+        }
+
+        Ok(update)
+    }
+
+    /// Names of all collections containing the resource, sorted.
+    pub fn find_everywhere(&self, id: &ResourceId) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .locations
+            .get(id)
+            .map(|names| names.iter().map(String::as_str).collect())
+            .unwrap_or_default();
+        names.sort_unstable();
+        names
+    }
+
+    fn forget_location(&mut self, id: &ResourceId, name: &str) {
+        if let Some(names) = self.locations.get_mut(id) {
+            names.remove(name);
+            if names.is_empty() {
+                self.locations.remove(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn resources_are_found_across_collections() {
+        let photos = tempfile::Builder::new()
+            .prefix("photos")
+            .tempdir()
+            .unwrap();
+        let archive = tempfile::Builder::new()
+            .prefix("archive")
+            .tempdir()
+            .unwrap();
+        fs::write(photos.path().join("cat.jpg"), b"cat").unwrap();
+        fs::write(photos.path().join("dog.jpg"), b"dog").unwrap();
+        fs::write(archive.path().join("old-cat.jpg"), b"cat").unwrap();
+
+        let mut collections = ResourceCollections::new();
+        collections
+            .insert("Photos", ResourceIndex::build(photos.path()).unwrap());
+        collections
+            .insert("Archive", ResourceIndex::build(archive.path()).unwrap());
+
+        let cat = ResourceId::from_bytes(b"cat");
+        let dog = ResourceId::from_bytes(b"dog");
+        assert_eq!(collections.find_everywhere(&cat), ["Archive", "Photos"]);
+        assert_eq!(collections.find_everywhere(&dog), ["Photos"]);
+
+        fs::remove_file(archive.path().join("old-cat.jpg")).unwrap();
+        let update = collections.update("Archive").unwrap();
+        assert!(update.deleted.contains(&cat));
+        assert_eq!(collections.find_everywhere(&cat), ["Photos"]);
+        assert_eq!(collections.get("Photos").unwrap().size(), 2);
+    }
+}
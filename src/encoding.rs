@@ -0,0 +1,101 @@
+use std::ffi::{OsStr, OsString};
+
+use anyhow::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Serialized form of paths and file names. Valid UTF-8 is stored
+/// as a plain string, anything else as base64 of the raw bytes
+/// (UTF-16 code units on Windows), so non-UTF-8 names survive
+/// a round-trip unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub(crate) enum EncodedOsStr {
+    Utf8(String),
+    Raw { base64: String },
+}
+
+impl From<&OsStr> for EncodedOsStr {
+    fn from(value: &OsStr) -> Self {
+        match value.to_str() {
+            Some(utf8) => EncodedOsStr::Utf8(utf8.to_owned()),
+            None => EncodedOsStr::Raw {
+                base64: base64::encode(raw_bytes(value)),
+            },
+        }
+    }
+}
+
+impl TryFrom<EncodedOsStr> for OsString {
+    type Error = Error;
+
+    fn try_from(value: EncodedOsStr) -> Result<Self, Error> {
+        match value {
+            EncodedOsStr::Utf8(utf8) => Ok(OsString::from(utf8)),
+            EncodedOsStr::Raw { base64 } => {
+                from_raw_bytes(base64::decode(base64)?)
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn raw_bytes(value: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    value.as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<OsString, Error> {
+    use std::os::unix::ffi::OsStringExt;
+
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+pub(crate) fn raw_bytes(value: &OsStr) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+
+    value
+        .encode_wide()
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+#[cfg(windows)]
+fn from_raw_bytes(bytes: Vec<u8>) -> Result<OsString, Error> {
+    use std::os::windows::ffi::OsStringExt;
+
+    if bytes.len() % 2 != 0 {
+        return Err(Error::msg("Odd number of bytes in UTF-16 name"));
+    }
+    let wide: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    Ok(OsString::from_wide(&wide))
+}
+
+/// For `#[serde(with = ...)]` on `Option<OsString>` fields.
+pub(crate) mod option_os_str {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<OsString>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value
+            .as_deref()
+            .map(EncodedOsStr::from)
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<OsString>, D::Error> {
+        Option::<EncodedOsStr>::deserialize(deserializer)?
+            .map(OsString::try_from)
+            .transpose()
+            .map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,56 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::index::IndexUpdate;
+
+/// Errors callers may want to tell apart, returned wrapped
+/// in `anyhow::Error` and recognized by `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArkError {
+    /// The root doesn't exist anymore, e.g. its drive was disconnected
+    /// or it was deleted. Updating would report all its content as
+    /// deleted, so nothing is done.
+    RootMissing(PathBuf),
+    /// The root exists, but isn't a directory or can't be accessed.
+    /// Nothing is done, as with `RootMissing`.
+    RootUnavailable(PathBuf),
+    /// An update would delete `gone` of `total` indexed paths, more
+    /// than `IndexOptions::mass_deletion_guard` allows. The update
+    /// wasn't applied, `update_force` applies it.
+    MassDeletionBlocked {
+        gone: usize,
+        total: usize,
+        update: IndexUpdate,
+    },
+    /// The file is empty and `EmptyHandling::Skip` is configured.
+    EmptyResource(PathBuf),
+    /// The root is the root of a filesystem or a system directory, e.g.
+    /// `/` or `C:\`, and `IndexOptions::allow_system_roots` isn't set.
+    SystemRoot(PathBuf),
+}
+
+impl fmt::Display for ArkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArkError::RootMissing(root) => {
+                write!(f, "Root {} is missing", root.display())
+            }
+            ArkError::RootUnavailable(root) => {
+                write!(f, "Root {} is unavailable", root.display())
+            }
+            ArkError::MassDeletionBlocked { gone, total, .. } => write!(
+                f,
+                "Update would delete {} of {} paths, refused",
+                gone, total
+            ),
+            ArkError::EmptyResource(path) => {
+                write!(f, "{} is empty", path.display())
+            }
+            ArkError::SystemRoot(root) => {
+                write!(f, "Root {} is a system directory", root.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArkError {}
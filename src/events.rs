@@ -0,0 +1,25 @@
+use std::time::SystemTime;
+
+use canonical_path::CanonicalPathBuf;
+
+use crate::id::ResourceId;
+
+/// Single mutation of the index, recorded when
+/// `IndexOptions::event_log` is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEvent {
+    pub at: SystemTime,
+    pub path: CanonicalPathBuf,
+    pub kind: IndexEventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexEventKind {
+    Added(ResourceId),
+    Deleted(ResourceId),
+    /// The path is preserved, but its content changed.
+    Changed {
+        old: ResourceId,
+        new: ResourceId,
+    },
+}
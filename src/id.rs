@@ -1,62 +1,298 @@
+use std::fmt;
 use std::fs;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::Path;
+use std::str::FromStr;
 
 use crc32fast::Hasher;
-use log;
+use serde::{Deserialize, Serialize};
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+use crate::encoding;
+
+#[derive(
+    Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Serialize, Deserialize,
+)]
 pub struct ResourceId {
     file_size: u64,
     crc32: u32,
+    /// Ids produced by different algorithms are never equal,
+    /// even if their sizes and checksums happen to match.
+    #[serde(default, skip_serializing_if = "AlgorithmId::is_default")]
+    algorithm: AlgorithmId,
+    /// Revision of the way ids are computed, see `ID_VERSION`.
+    /// Ids stored before versioning are of the first one.
+    #[serde(default = "first_version")]
+    version: u8,
+}
+
+/// Version of the ids computed by this crate. Changing the way
+/// the size and the checksum are combined bumps it, so ids stored
+/// before are recognized, see `ResourceId::try_eq`.
+pub const ID_VERSION: u8 = 1;
+
+fn first_version() -> u8 {
+    1
+}
+
+/// How the checksum of a `ResourceId` was computed.
+#[derive(
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Serialize,
+    Deserialize,
+)]
+pub enum AlgorithmId {
+    /// CRC-32 of the whole content.
+    #[default]
+    Crc32,
+    /// CRC-32 of the first bytes only, see `ResourceId::partial`.
+    Crc32Prefix(u64),
+}
+
+impl AlgorithmId {
+    fn is_default(&self) -> bool {
+        *self == AlgorithmId::default()
+    }
+}
+
+impl fmt::Display for ResourceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}:{}-{}", self.version, self.file_size, self.crc32)?;
+        match self.algorithm {
+            AlgorithmId::Crc32 => Ok(()),
+            AlgorithmId::Crc32Prefix(prefix_len) => {
+                write!(f, "-p{}", prefix_len)
+            }
+        }
+    }
+}
+
+impl FromStr for ResourceId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || anyhow::Error::msg(format!("Malformed id {}", s));
+        // ids formatted before versioning have no prefix
+        let (version, id) = match s.strip_prefix('v') {
+            Some(versioned) => {
+                let (version, id) =
+                    versioned.split_once(':').ok_or_else(malformed)?;
+                (version.parse()?, id)
+            }
+            None => (first_version(), s),
+        };
+        let mut parts = id.splitn(3, '-');
+        let file_size = parts.next().ok_or_else(malformed)?.parse()?;
+        let crc32 = parts.next().ok_or_else(malformed)?.parse()?;
+        let algorithm = match parts.next() {
+            None => AlgorithmId::Crc32,
+            Some(prefix_len) => AlgorithmId::Crc32Prefix(
+                prefix_len
+                    .strip_prefix('p')
+                    .ok_or_else(malformed)?
+                    .parse()?,
+            ),
+        };
+        Ok(ResourceId {
+            file_size,
+            crc32,
+            algorithm,
+            version,
+        })
+    }
+}
+
+/// Incremental computation of a `ResourceId` for content
+/// arriving in chunks, e.g. from a network stream or a pipe.
+/// Feeding the chunks of some content yields the same id
+/// as `ResourceId::from_bytes` over the whole content.
+#[derive(Clone, Default)]
+pub struct ResourceIdHasher {
+    hasher: Hasher,
+    bytes_read: u64,
+}
+
+impl ResourceIdHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.hasher.update(bytes);
+        self.bytes_read += bytes.len() as u64;
+    }
+
+    pub fn finalize(self) -> ResourceId {
+        ResourceId {
+            file_size: self.bytes_read,
+            crc32: self.hasher.finalize(),
+            algorithm: AlgorithmId::Crc32,
+            version: ID_VERSION,
+        }
+    }
 }
 
 impl ResourceId {
-    pub fn compute<P: AsRef<Path>>(file_size: u64, file_path: P) -> Self {
-        log::trace!(
-            "Calculating hash of {} (given size is {} megabytes)",
-            file_path.as_ref().display(),
-            file_size / MEGABYTE
-        );
+    /// Size of the identified content in bytes.
+    pub fn file_size(&self) -> u64 {
+        self.file_size
+    }
 
-        let source = fs::OpenOptions::new()
-            .read(true)
-            .open(file_path.as_ref())
-            .expect(&format!(
-                "Failed to read from {}",
-                file_path.as_ref().display()
-            ));
+    /// Algorithm which produced the id.
+    pub fn algorithm(&self) -> AlgorithmId {
+        self.algorithm
+    }
 
+    /// Version of the id, `ID_VERSION` for ids computed now.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Whether the ids can be compared: they were computed by the
+    /// same version and algorithm.
+    pub fn compatible_with(&self, other: &ResourceId) -> bool {
+        self.version == other.version && self.algorithm == other.algorithm
+    }
+
+    /// Whether the ids identify the same content. Unlike `==`,
+    /// which tells incompatible ids unequal, fails for them,
+    /// e.g. an id stored by an older version of the crate.
+    pub fn try_eq(&self, other: &ResourceId) -> Result<bool, anyhow::Error> {
+        if !self.compatible_with(other) {
+            return Err(anyhow::Error::msg(format!(
+                "Ids {} and {} are incompatible",
+                self, other
+            )));
+        }
+        Ok(self == other)
+    }
+
+    /// Id of an empty file which depends on its path, so that empty
+    /// files don't collide, see `EmptyHandling::DistinctById`.
+    pub(crate) fn of_empty_at(path: &Path) -> Self {
+        let mut hasher = ResourceIdHasher::new();
+        hasher.update(&encoding::raw_bytes(path.as_os_str()));
+        ResourceId {
+            file_size: 0,
+            ..hasher.finalize()
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut hasher = ResourceIdHasher::new();
+        hasher.update(bytes);
+        hasher.finalize()
+    }
+
+    pub fn from_reader<R: Read>(source: R) -> std::io::Result<Self> {
         let mut reader = BufReader::with_capacity(BUFFER_CAPACITY, source);
         assert!(reader.buffer().is_empty());
 
-        let mut hasher = Hasher::new();
-        let mut bytes_read: u32 = 0;
+        let mut hasher = ResourceIdHasher::new();
         loop {
-            let bytes_read_iteration: usize = reader
-                .fill_buf()
-                .expect(&format!(
-                    "Failed to read from {}",
-                    file_path.as_ref().display()
-                ))
-                .len();
+            let bytes_read_iteration: usize = reader.fill_buf()?.len();
             if bytes_read_iteration == 0 {
                 break;
             }
             hasher.update(reader.buffer());
             reader.consume(bytes_read_iteration);
-            bytes_read += u32::try_from(bytes_read_iteration).expect(&format!(
-                "Failed to read from {}",
-                file_path.as_ref().display()
-            ))
         }
 
-        let crc32: u32 = hasher.finalize().into();
-        log::trace!("{} bytes has been read", bytes_read);
-        log::trace!("checksum: {:#02x}", crc32);
-        assert!(bytes_read == file_size.try_into().unwrap());
+        Ok(hasher.finalize())
+    }
 
-        ResourceId { file_size, crc32 }
+    /// Same as `from_reader`, but fails if the amount of content
+    /// differs from `expected_size`. Tells a file which could not be
+    /// read, or was truncated while reading, apart from an empty one.
+    pub fn from_sized_reader<R: Read>(
+        expected_size: u64,
+        source: R,
+    ) -> io::Result<Self> {
+        let id = Self::from_reader(source)?;
+        if id.file_size == expected_size {
+            return Ok(id);
+        }
+
+        let reason = if id.file_size == 0 {
+            format!("nothing was read, expected {} bytes", expected_size)
+        } else {
+            format!(
+                "{} bytes were read, expected {} bytes",
+                id.file_size, expected_size
+            )
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+    }
+
+    /// Cheap id built from the file size and its first `prefix_len`
+    /// bytes. Different partial ids mean different content, but equal
+    /// partial ids only make the files candidates for being the same.
+    /// Never delete or merge files based on partial ids alone,
+    /// confirm with the full id first. Files not longer than the
+    /// prefix are read whole and get their full id.
+    pub fn partial<P: AsRef<Path>>(
+        file_path: P,
+        prefix_len: u64,
+    ) -> io::Result<Self> {
+        let source = fs::File::open(file_path)?;
+        let file_size = source.metadata()?.len();
+        Self::partial_from_reader(file_size, source, prefix_len)
+    }
+
+    pub(crate) fn partial_from_reader<R: Read>(
+        file_size: u64,
+        source: R,
+        prefix_len: u64,
+    ) -> io::Result<Self> {
+        let prefix = Self::from_sized_reader(
+            file_size.min(prefix_len),
+            source.take(prefix_len),
+        )?;
+        if file_size <= prefix_len {
+            return Ok(prefix);
+        }
+        Ok(ResourceId {
+            file_size,
+            crc32: prefix.crc32,
+            algorithm: AlgorithmId::Crc32Prefix(prefix_len),
+            version: ID_VERSION,
+        })
+    }
+
+    pub fn compute<P: AsRef<Path>>(file_size: u64, file_path: P) -> Self {
+        log::trace!(
+            "Calculating hash of {} (given size is {} megabytes)",
+            file_path.as_ref().display(),
+            file_size / MEGABYTE
+        );
+
+        let source = fs::OpenOptions::new()
+            .read(true)
+            .open(file_path.as_ref())
+            .unwrap_or_else(|_| {
+                panic!("Failed to read from {}", file_path.as_ref().display())
+            });
+
+        let id =
+            Self::from_sized_reader(file_size, source).unwrap_or_else(|err| {
+                panic!(
+                    "Failed to read from {}: {}",
+                    file_path.as_ref().display(),
+                    err
+                )
+            });
+
+        log::trace!("{} bytes has been read", id.file_size);
+        log::trace!("checksum: {:#02x}", id.crc32);
+
+        id
     }
 }
 
@@ -72,13 +308,131 @@ mod tests {
     fn compute_id_test() {
         let file_path = Path::new("./tests/lena.jpg");
         let file_size = fs::metadata(file_path)
-            .expect(&format!(
-                "Could not open image test file_path.{}",
-                file_path.display()
-            ))
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Could not open image test file_path.{}",
+                    file_path.display()
+                )
+            })
             .len();
 
-        let id = ResourceId::compute(file_size.try_into().unwrap(), file_path);
+        let id = ResourceId::compute(file_size, file_path);
         assert_eq!(id.crc32, 0x342a3d4a);
     }
+
+    #[test]
+    fn hasher_matches_from_bytes() {
+        let content = fs::read("./tests/lena.jpg").unwrap();
+
+        let mut hasher = ResourceIdHasher::new();
+        for chunk in content.chunks(1000) {
+            hasher.update(chunk);
+        }
+        let streamed = hasher.finalize();
+
+        assert_eq!(streamed, ResourceId::from_bytes(&content));
+        assert_eq!(streamed, ResourceId::from_reader(&content[..]).unwrap());
+        assert_eq!(streamed.crc32, 0x342a3d4a);
+    }
+
+    #[test]
+    fn zero_length_read_is_not_empty_content() {
+        let err = ResourceId::from_sized_reader(100, io::empty()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = ResourceId::from_sized_reader(100, &[0u8; 10][..]);
+        assert!(truncated.is_err());
+
+        let empty = ResourceId::from_sized_reader(0, io::empty()).unwrap();
+        assert_eq!(empty, ResourceId::from_bytes(&[]));
+    }
+
+    #[test]
+    fn partial_id_compares_prefix_and_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &[u8]| {
+            let path = dir.path().join(name);
+            fs::write(&path, content).unwrap();
+            path
+        };
+        let a = write("a", b"same prefix, tail A");
+        let b = write("b", b"same prefix, tail B");
+        let c = write("c", b"same prefix, longer tail");
+
+        let partial = |path| ResourceId::partial(path, 11).unwrap();
+        assert_eq!(partial(&a), partial(&b));
+        assert_ne!(partial(&a), partial(&c));
+        assert_ne!(ResourceId::compute(19, &a), ResourceId::compute(19, &b));
+    }
+
+    #[test]
+    fn id_string_round_trip() {
+        let id = ResourceId::from_bytes(b"content");
+        assert_eq!(id.to_string().parse::<ResourceId>().unwrap(), id);
+        assert!("7".parse::<ResourceId>().is_err());
+        assert!("7-x".parse::<ResourceId>().is_err());
+        assert!("7-1-x".parse::<ResourceId>().is_err());
+        assert!("v1-7-1".parse::<ResourceId>().is_err());
+        assert_eq!(id.to_string(), format!("v1:7-{}", id.crc32));
+        assert_eq!(
+            format!("7-{}", id.crc32)
+                .parse::<ResourceId>()
+                .unwrap(),
+            id
+        );
+
+        let partial = ResourceId {
+            algorithm: AlgorithmId::Crc32Prefix(4),
+            ..id
+        };
+        assert_eq!(partial.to_string().parse::<ResourceId>().unwrap(), partial);
+    }
+
+    #[test]
+    fn ids_of_different_versions_are_incompatible() {
+        let current = ResourceId::from_bytes(b"content");
+        assert_eq!(current.version(), ID_VERSION);
+        let next = ResourceId {
+            version: ID_VERSION + 1,
+            ..current.clone()
+        };
+
+        assert!(!current.compatible_with(&next));
+        assert!(current.try_eq(&next).is_err());
+        assert!(current.try_eq(&current.clone()).unwrap());
+        assert_eq!(next.to_string().parse::<ResourceId>().unwrap(), next);
+
+        let json = serde_json::to_string(&next).unwrap();
+        let parsed: ResourceId = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.version(), ID_VERSION + 1);
+        let legacy = r#"{"file_size":7,"crc32":1}"#;
+        let parsed: ResourceId = serde_json::from_str(legacy).unwrap();
+        assert_eq!(parsed.version(), 1);
+    }
+
+    #[test]
+    fn ids_of_different_algorithms_differ() {
+        let full = ResourceId::from_bytes(b"content");
+        let partial = ResourceId {
+            algorithm: AlgorithmId::Crc32Prefix(4),
+            ..full.clone()
+        };
+        assert_eq!(full.algorithm(), AlgorithmId::Crc32);
+        assert_ne!(full, partial);
+
+        let json = serde_json::to_string(&full).unwrap();
+        assert!(!json.contains("algorithm"));
+        let parsed: ResourceId = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, full);
+        let json = serde_json::to_string(&partial).unwrap();
+        let parsed: ResourceId = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, partial);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        fs::write(&path, b"content").unwrap();
+        let short = ResourceId::partial(&path, 4).unwrap();
+        assert_eq!(short.algorithm(), AlgorithmId::Crc32Prefix(4));
+        assert_eq!(ResourceId::partial(&path, 100).unwrap(), full);
+    }
 }
@@ -1,270 +1,6270 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, BufRead, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use canonical_path::CanonicalPathBuf;
+use canonical_path::{CanonicalPath, CanonicalPathBuf};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use walkdir::{DirEntry, WalkDir};
 
 use anyhow::Error;
-use log;
+use log::Level;
 
-use crate::id::ResourceId;
-use crate::meta::ResourceMeta;
+use crate::cache::ChecksumCache;
+use crate::encoding::EncodedOsStr;
+use crate::error::ArkError;
+use crate::events::{IndexEvent, IndexEventKind};
+use crate::id::{AlgorithmId, ResourceId};
+use crate::meta::{FileNode, Resource, ResourceFileType, ResourceMeta};
+
+/// Logs the message and passes it to `IndexOptions::logger`, if any.
+macro_rules! report {
+    ($options:expr, $level:expr, $($arg:tt)+) => {{
+        let message = format!($($arg)+);
+        log::log!($level, "{}", message);
+        if let Some(logger) = $options.logger.as_ref() {
+            logger.emit($level, &message);
+        }
+    }};
+}
 
 #[derive(Debug)]
 pub struct ResourceIndex {
     pub path2meta: HashMap<CanonicalPathBuf, ResourceMeta>,
     pub collisions: HashMap<ResourceId, usize>,
     ids: HashSet<ResourceId>,
-    root: PathBuf,
+    roots: Vec<IndexRoot>,
+    options: IndexOptions,
+    events: VecDeque<IndexEvent>,
+    streams: HashMap<String, ResourceId>,
+    suspicious: HashSet<CanonicalPathBuf>,
+    updated: Option<SystemTime>,
+    scan_errors: usize,
+    tags: HashMap<ResourceId, HashSet<String>>,
+    /// Whether the index changed since it was stored or loaded.
+    dirty: AtomicBool,
+    /// Modification times of the directories seen by the last walk,
+    /// see `IndexOptions::trust_dir_mtimes`.
+    dir_times: DirTimes,
+    on_update: Vec<(HookHandle, UpdateHook)>,
+    next_hook: u64,
+    /// Count of updates which changed the index, stored with it.
+    generation: u64,
+    /// Where the index is stored after every update,
+    /// see `ResourceIndex::checkpoint_to`.
+    checkpoint: Option<PathBuf>,
 }
 
-#[derive(Debug)]
-pub struct IndexUpdate {
-    pub deleted: HashSet<ResourceId>,
-    pub added: HashMap<CanonicalPathBuf, ResourceMeta>,
+type UpdateFn = dyn FnMut(&IndexUpdate) + Send + Sync;
+
+struct UpdateHook(Box<UpdateFn>);
+
+/// Identifies a hook registered by `on_update` or `watch_subtree`,
+/// see `ResourceIndex::remove_hook`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookHandle(u64);
+
+impl std::fmt::Debug for UpdateHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UpdateHook")
+    }
 }
 
-impl ResourceIndex {
-    pub fn size(&self) -> usize {
-        //the actual size is lower in presence of collisions
-        self.path2meta.len()
+/// Options are stored along with the index, so that a loaded index
+/// is updated the way it was built. Callbacks can't be stored and
+/// have to be set again after loading.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexOptions {
+    /// Hash files in their approximate on-disk order (by inode on Unix,
+    /// by path elsewhere) and ask the kernel to prefetch the next file
+    /// while the current one is hashed. Helps on spinning disks,
+    /// the resulting index is the same as with the default scan.
+    pub sequential_scan: bool,
+    /// How many files are hashed at once. Ignored by `sequential_scan`,
+    /// which reads one file after another.
+    pub parallelism: Parallelism,
+    /// Most files hashed at once while scanning, e.g. under a low limit
+    /// of file descriptors. Every hashing thread keeps one file open, so
+    /// no more than this many threads are started. `sequential_scan`
+    /// hashes one file at a time anyway. Files opened elsewhere in the
+    /// process aren't counted: opens failing for lack of descriptors are
+    /// only retried when `retry` allows more than one attempt.
+    pub max_open_files: Option<usize>,
+    /// How symbolic links are indexed.
+    pub symlinks: SymlinkMode,
+    /// How empty files are indexed.
+    pub empty_file_handling: EmptyHandling,
+    /// What to do when a directory is reached the second time
+    /// while following symlinks.
+    pub symlink_loops: SymlinkLoops,
+    /// Identify files by their size and first bytes only, see
+    /// `ResourceId::partial`. Such an index tells which files are
+    /// *probably* the same and must never drive deletion or any other
+    /// destructive deduplication without confirming by full hashes.
+    pub partial_hash: Option<u64>,
+    /// Record every mutation made after the index was built,
+    /// see `ResourceIndex::drain_events`.
+    pub event_log: bool,
+    /// Keep only this many latest events, older ones are discarded.
+    pub event_log_capacity: Option<usize>,
+    /// Content which is never indexed, e.g. placeholders found
+    /// all over the place. Files are still hashed to be recognized.
+    #[serde(serialize_with = "sorted")]
+    pub blocked_ids: HashSet<ResourceId>,
+    /// Count hardlinks of the same file as a single copy in
+    /// `duplicates` and the views built on it, so content already
+    /// deduplicated by hardlinking isn't reported again.
+    /// `collisions` keeps counting every path.
+    pub collapse_hardlinks: bool,
+    /// How filesystem calls failing for a moment, e.g. on network
+    /// drives, are retried before a file is given up.
+    pub retry: RetryPolicy,
+    /// Names starting with a dot which are indexed nonetheless,
+    /// e.g. `.env`. Listed directories are walked into.
+    #[serde(serialize_with = "sorted")]
+    pub hidden_exceptions: HashSet<String>,
+    /// Tells which entries are hidden instead of their leading dot,
+    /// e.g. to honor `FILE_ATTRIBUTE_HIDDEN` on Windows. Hidden
+    /// directories are not walked into, `hidden_exceptions` is unused.
+    #[serde(skip)]
+    pub hidden_fn: Option<HiddenFilter>,
+    /// Largest fraction of indexed paths a single `update` may delete,
+    /// e.g. `0.5`. Beyond it, the update is refused as a probable mount
+    /// problem or mistake, see `ResourceIndex::update_force`.
+    pub mass_deletion_guard: Option<f64>,
+    /// Re-hash every known file during `update`, not only the ones with
+    /// a newer modification time, to catch edits on storage which
+    /// doesn't maintain mtimes. Costs as much as building the index
+    /// anew, see `ResourceIndex::mark_suspicious` for a single file.
+    pub verify_content_on_update: bool,
+    /// Copy tags of the old content to the new one when a file is
+    /// edited in place, so annotations survive edits. Tags stay with
+    /// the old content as long as other copies of it are indexed.
+    pub carry_metadata_on_edit: bool,
+    /// Let `update` skip the walk when no directory changed its
+    /// modification time since the previous walk. Creating, deleting
+    /// and renaming files touches their directory, but editing a file
+    /// in place doesn't, so such edits are only found by the next
+    /// update which walks. Directories touched shortly before the walk
+    /// are never trusted, the next update walks again.
+    pub trust_dir_mtimes: bool,
+    /// Index a whole filesystem or a system directory, e.g. `/`, which
+    /// is refused with `ArkError::SystemRoot` otherwise. Pseudo
+    /// filesystems such as `/proc` are skipped regardless.
+    pub allow_system_roots: bool,
+    /// What becomes of names ending with a dot or a space on Windows,
+    /// which the usual Win32 path form silently trims.
+    pub trailing_dot_names: TrailingDotNames,
+    /// Receives progress and failures of building and updating,
+    /// in addition to the `log` facade.
+    #[serde(skip)]
+    pub logger: Option<LogSink>,
+    /// Tags files as they are indexed, e.g. by extension, in addition
+    /// to the tags given by `ResourceIndex::tag`.
+    #[serde(skip)]
+    pub classifier: Option<Classifier>,
+    /// Opens files for hashing instead of `File::open`, e.g. to read
+    /// through a decrypting layer or to count descriptors in use.
+    #[serde(skip)]
+    pub opener: Option<Opener>,
+}
+
+fn sorted<S, T>(set: &HashSet<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Ord + Serialize,
+{
+    set.iter()
+        .collect::<BTreeSet<_>>()
+        .serialize(serializer)
+}
+
+type LogFn = dyn Fn(Level, &str) + Send + Sync;
+
+/// Callback receiving log messages of an index, e.g. to show
+/// them in the console of an application.
+#[derive(Clone)]
+pub struct LogSink(Arc<LogFn>);
+
+impl LogSink {
+    pub fn new<F>(sink: F) -> Self
+    where
+        F: Fn(Level, &str) + Send + Sync + 'static,
+    {
+        LogSink(Arc::new(sink))
     }
 
-    pub fn build<P: AsRef<Path>>(root_path: P) -> Result<Self, Error> {
-        log::info!("Creating the index from scratch");
+    fn emit(&self, level: Level, message: &str) {
+        (self.0)(level, message)
+    }
+}
 
-        let paths = discover_paths(root_path.as_ref().to_owned());
-        let metadata = scan_metadata(paths);
+impl std::fmt::Debug for LogSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LogSink")
+    }
+}
 
-        let mut index = ResourceIndex {
-            path2meta: HashMap::new(),
-            collisions: HashMap::new(),
-            ids: HashSet::new(),
-            root: root_path.as_ref().to_owned(),
-        };
+type HiddenFn = dyn Fn(&DirEntry) -> bool + Send + Sync;
 
-        for (path, meta) in metadata {
-            add_meta(
-                path,
-                meta,
-                &mut index.path2meta,
-                &mut index.collisions,
-                &mut index.ids,
-            );
-        }
+/// Predicate telling which entries are hidden, see
+/// `IndexOptions::hidden_fn`.
+#[derive(Clone)]
+pub struct HiddenFilter(Arc<HiddenFn>);
 
-        log::info!("Index built");
-        return Ok(index);
+impl HiddenFilter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&DirEntry) -> bool + Send + Sync + 'static,
+    {
+        HiddenFilter(Arc::new(filter))
     }
+}
 
-    pub fn update(&mut self) -> Result<IndexUpdate, Error> {
-        log::info!("Updating the index");
-        log::trace!("Known paths:\n{:?}", self.path2meta.keys());
+impl std::fmt::Debug for HiddenFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("HiddenFilter")
+    }
+}
 
-        let curr_entries = discover_paths(self.root.clone());
+type ClassifierFn =
+    dyn Fn(&CanonicalPathBuf, &ResourceMeta) -> Vec<String> + Send + Sync;
 
-        //assuming that collections manipulation is
-        // quicker than asking `path.exists()` for every path
-        let curr_paths: Paths = curr_entries.keys().cloned().collect();
-        let prev_paths: Paths = self.path2meta.keys().cloned().collect();
-        let preserved_paths: Paths = curr_paths
-            .intersection(&prev_paths)
-            .cloned()
-            .collect();
+/// Callback giving tags of newly indexed files, see
+/// `IndexOptions::classifier`.
+#[derive(Clone)]
+pub struct Classifier(Arc<ClassifierFn>);
 
-        let created_paths: HashMap<CanonicalPathBuf, DirEntry> = curr_entries
-            .iter()
-            .filter_map(|(path, entry)| {
-                if !preserved_paths.contains(path.as_canonical_path()) {
-                    Some((path.clone(), entry.clone()))
-                } else {
-                    None
-                }
-            })
-            .collect();
+impl Classifier {
+    pub fn new<F>(classifier: F) -> Self
+    where
+        F: Fn(&CanonicalPathBuf, &ResourceMeta) -> Vec<String>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Classifier(Arc::new(classifier))
+    }
+}
 
-        log::info!("Checking updated paths");
-        let updated_paths: HashMap<CanonicalPathBuf, DirEntry> = curr_entries
-            .into_iter()
-            .filter(|(path, entry)| {
-                if !preserved_paths.contains(path.as_canonical_path()) {
-                    false
-                } else {
-                    let prev_modified = self.path2meta[path].modified;
+impl std::fmt::Debug for Classifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Classifier")
+    }
+}
 
-                    let result = entry.metadata();
-                    match result {
-                        Err(msg) => {
-                            log::error!(
-                                "Couldn't retrieve metadata for {}: {}",
-                                &path.display(),
-                                msg
-                            );
-                            false
-                        }
-                        Ok(metadata) => match metadata.modified() {
-                            Err(msg) => {
-                                log::error!(
-                                    "Couldn't retrieve timestamp for {}: {}",
-                                    &path.display(),
-                                    msg
-                                );
-                                false
-                            }
-                            Ok(curr_modified) => curr_modified > prev_modified,
-                        },
-                    }
-                }
-            })
-            .collect();
+type OpenFn = dyn Fn(&Path) -> io::Result<Box<dyn Read + Send>> + Send + Sync;
 
-        let mut deleted: HashSet<ResourceId> = HashSet::new();
+/// Callback opening files to be hashed, see `IndexOptions::opener`.
+#[derive(Clone)]
+pub struct Opener(Arc<OpenFn>);
 
-        // treating deleted and updated paths as deletions
-        prev_paths
-            .difference(&preserved_paths)
-            .cloned()
-            .chain(updated_paths.keys().cloned())
-            .for_each(|path| {
-                if let Some(meta) = self.path2meta.remove(&path) {
-                    let k = self.collisions.remove(&meta.id).unwrap_or(1);
-                    if k > 1 {
-                        self.collisions.insert(meta.id, k - 1);
-                    } else {
-                        log::debug!("Removing {:?} from index", meta.id);
-                        self.ids.remove(&meta.id);
-                        deleted.insert(meta.id);
-                    }
-                } else {
-                    log::warn!("Path {} was not known", path.display());
-                }
-            });
+impl Opener {
+    pub fn new<F>(opener: F) -> Self
+    where
+        F: Fn(&Path) -> io::Result<Box<dyn Read + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Opener(Arc::new(opener))
+    }
 
-        let added: HashMap<CanonicalPathBuf, ResourceMeta> =
-            scan_metadata(updated_paths)
-                .into_iter()
-                .chain({
-                    log::info!("The same for new paths");
-                    scan_metadata(created_paths).into_iter()
-                })
-                .filter(|(_, meta)| !self.ids.contains(&meta.id))
-                .collect();
+    pub(crate) fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        (self.0)(path)
+    }
+}
 
-        for (path, meta) in added.iter() {
-            if deleted.contains(&meta.id) {
-                // emitting the resource as both deleted and added
-                // (renaming a duplicate might remain undetected)
-                log::info!(
-                    "Resource {:?} was moved to {}",
-                    meta.id,
-                    path.display()
-                );
+impl std::fmt::Debug for Opener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Opener")
+    }
+}
+
+/// Retrying of transient IO errors. Missing files are never retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    /// Tries in total, 1 means no retries.
+    pub attempts: u32,
+    /// Pause before the first retry, doubled before each next one.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 1,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn retry<T, F>(&self, mut operation: F) -> io::Result<T>
+    where
+        F: FnMut() -> io::Result<T>,
+    {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(err)
+                    if attempt < self.attempts
+                        && err.kind() != io::ErrorKind::NotFound =>
+                {
+                    log::warn!("Retrying after {}", err);
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
             }
+        }
+    }
+}
 
-            add_meta(
-                path.clone(),
-                meta.clone(),
-                &mut self.path2meta,
-                &mut self.collisions,
-                &mut self.ids,
-            );
+/// Directory indexed by a `ResourceIndex`. Several roots make
+/// a single index deduplicating content across all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexRoot {
+    pub path: PathBuf,
+    /// Lowercase extensions, without the dot, of the files indexed
+    /// under this root. Everything is indexed when `None`.
+    pub extensions: Option<HashSet<String>>,
+}
+
+impl IndexRoot {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        IndexRoot {
+            path: path.into(),
+            extensions: None,
+        }
+    }
+
+    pub fn with_extensions<P, I, S>(path: P, extensions: I) -> Self
+    where
+        P: Into<PathBuf>,
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        IndexRoot {
+            path: path.into(),
+            extensions: Some(
+                extensions
+                    .into_iter()
+                    .map(|extension| extension.as_ref().to_lowercase())
+                    .collect(),
+            ),
         }
+    }
 
-        Ok(IndexUpdate { deleted, added })
+    fn discover(
+        &self,
+        options: &IndexOptions,
+        dirs: &mut DirTimes,
+        excluded: &Exclusions,
+    ) -> Result<HashMap<CanonicalPathBuf, DirEntry>, Error> {
+        let extensions = self.extensions.as_ref();
+        discover_with_dirs(&self.path, options, extensions, dirs, excluded)
     }
 }
 
-fn discover_paths<P: AsRef<Path>>(
-    root_path: P,
-) -> HashMap<CanonicalPathBuf, DirEntry> {
-    log::info!(
-        "Discovering all files under path {}",
-        root_path.as_ref().display()
-    );
+/// Concurrency of hashing. Parallel reads pay off on SSDs, while
+/// on spinning disks they make the heads seek between the files
+/// and are slower than `Serial`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum Parallelism {
+    #[default]
+    Serial,
+    /// One thread per available CPU.
+    Auto,
+    Fixed(usize),
+}
 
-    WalkDir::new(root_path)
-        .into_iter()
-        .filter_entry(|entry| !is_hidden(entry))
-        .filter_map(|result| match result {
-            Ok(entry) => {
-                let path = entry.path();
-                if !entry.file_type().is_dir() {
-                    match CanonicalPathBuf::canonicalize(path) {
-                        Ok(canonical_path) => Some((canonical_path, entry)),
-                        Err(msg) => {
-                            log::error!(
-                                "Couldn't canonicalize {}:\n{}",
-                                path.display(),
-                                msg
-                            );
-                            None
-                        }
-                    }
-                } else {
-                    None
-                }
-            }
-            Err(msg) => {
-                log::error!("Error during walking: {}", msg);
-                None
-            }
-        })
-        .collect()
+impl Parallelism {
+    fn threads(self) -> usize {
+        match self {
+            Parallelism::Serial => 1,
+            Parallelism::Auto => std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            Parallelism::Fixed(n) => n.max(1),
+        }
+    }
 }
 
-fn scan_metadata(
-    entries: HashMap<CanonicalPathBuf, DirEntry>,
-) -> HashMap<CanonicalPathBuf, ResourceMeta> {
-    log::info!("Scanning metadata");
+/// Handling of Windows names ending with a dot or a space, such as
+/// `notes.` created by other systems or through `\\?\` paths. Opening
+/// them by their usual path reaches a different file or none at all.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum TrailingDotNames {
+    /// Such files aren't indexed and are reported
+    /// as `Exclusion::TrailingDotName`.
+    #[default]
+    Exclude,
+    /// Such files are indexed by their extended-length path,
+    /// which keeps the name as it is.
+    Preserve,
+}
 
-    entries
-        .into_iter()
-        .filter_map(|(path, entry)| {
-            log::trace!("\n\t{:?}\n\t\t{:?}", path, entry);
+/// How files without content are indexed.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum EmptyHandling {
+    /// Empty files aren't indexed.
+    #[default]
+    Skip,
+    /// Empty files share the id of empty content,
+    /// so they are all duplicates of each other.
+    SharedId,
+    /// Every empty file gets an id derived from its path, so it is
+    /// a resource of its own. The id stays the same while the file
+    /// stays at the path.
+    DistinctById,
+}
 
-            let result = ResourceMeta::scan(path.clone(), entry);
-            match result {
-                Err(msg) => {
-                    log::error!(
-                        "Couldn't retrieve metadata for {}:\n{}",
-                        path.display(),
-                        msg
-                    );
-                    None
-                }
-                Ok(meta) => Some(meta),
-            }
-        })
-        .collect()
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum SymlinkMode {
+    /// Symlinks are neither indexed nor descended into.
+    #[default]
+    Skip,
+    /// Symlinks are transparent: symlinked directories are walked and
+    /// symlinked files are indexed by their targets, at the targets'
+    /// canonical paths.
+    FollowTarget,
+    /// Every symlink is a resource of its own, at the link's path,
+    /// whose content is the target path. Moving or retargeting a link
+    /// is tracked as any other change, the target isn't read.
+    HashLinkTarget,
 }
 
-fn add_meta(
-    path: CanonicalPathBuf,
-    meta: ResourceMeta,
-    path2meta: &mut HashMap<CanonicalPathBuf, ResourceMeta>,
-    collisions: &mut HashMap<ResourceId, usize>,
-    ids: &mut HashSet<ResourceId>,
-) {
-    let id = meta.id.clone();
-    path2meta.insert(path, meta);
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize,
+)]
+pub enum SymlinkLoops {
+    /// Log a warning and don't descend into the directory again.
+    #[default]
+    Skip,
+    /// Abort the discovery with an error.
+    Fail,
+}
 
-    if ids.contains(&id) {
-        if let Some(nonempty) = collisions.get_mut(&id) {
-            *nonempty += 1;
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexUpdate {
+    pub deleted: HashSet<ResourceId>,
+    pub added: HashMap<CanonicalPathBuf, ResourceMeta>,
+    /// Paths which stayed but got new content, with the old and the
+    /// new id. The ids are reported in `deleted` and `added` as well
+    /// when the content disappeared or appeared.
+    pub modified_in_place: HashMap<CanonicalPathBuf, (ResourceId, ResourceId)>,
+}
+
+impl IndexUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty()
+            && self.added.is_empty()
+            && self.modified_in_place.is_empty()
+    }
+
+    /// Part of the update concerning `subdir`: resources added under it
+    /// and deleted content among `present`, the ids found there before.
+    pub fn filter_under(
+        &self,
+        subdir: &Path,
+        present: &HashSet<ResourceId>,
+    ) -> IndexUpdate {
+        IndexUpdate {
+            deleted: self
+                .deleted
+                .intersection(present)
+                .cloned()
+                .collect(),
+            added: self
+                .added
+                .iter()
+                .filter(|(path, _)| path.starts_with(subdir))
+                .map(|(path, meta)| (path.clone(), meta.clone()))
+                .collect(),
+            modified_in_place: self
+                .modified_in_place
+                .iter()
+                .filter(|(path, _)| path.starts_with(subdir))
+                .map(|(path, ids)| (path.clone(), ids.clone()))
+                .collect(),
+        }
+    }
+
+    /// Drops the metadata of added paths, keeping their ids.
+    pub fn into_light(self) -> LightUpdate {
+        LightUpdate {
+            deleted: self.deleted,
+            added: self
+                .added
+                .into_iter()
+                .map(|(path, meta)| (path, meta.id))
+                .collect(),
+            modified_in_place: self.modified_in_place,
+        }
+    }
+}
+
+/// `IndexUpdate` carrying only ids of added paths, e.g. to send
+/// many changes to another process, see `ResourceIndex::update_light`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LightUpdate {
+    pub deleted: HashSet<ResourceId>,
+    pub added: HashMap<CanonicalPathBuf, ResourceId>,
+    pub modified_in_place: HashMap<CanonicalPathBuf, (ResourceId, ResourceId)>,
+}
+
+/// Why a path was deliberately left out of the index,
+/// see `ResourceIndex::build_with_report`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Exclusion {
+    /// Hidden, or below a hidden directory which wasn't walked.
+    Hidden,
+    /// The extension isn't among the indexed ones of the root.
+    Extension,
+    /// Symlinks aren't indexed, see `SymlinkMode`.
+    Symlink,
+    /// The directory was walked already, through a symlink.
+    Revisited,
+    /// A pseudo filesystem such as `/proc`.
+    SystemDirectory,
+    /// Empty files aren't indexed, see `EmptyHandling`.
+    Empty,
+    /// See `IndexOptions::blocked_ids`.
+    Blocked,
+    /// A Windows name ending with a dot or a space,
+    /// see `TrailingDotNames`.
+    TrailingDotName,
+}
+
+/// Every path left out of an index by `build_with_report`,
+/// sorted by path. Paths below skipped directories aren't listed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BuildReport {
+    /// Paths the walk couldn't read, mostly directories,
+    /// with the error.
+    pub unreadable: Vec<(PathBuf, String)>,
+    /// Files which couldn't be hashed, with the error.
+    pub unscannable: Vec<(PathBuf, String)>,
+    pub filtered: Vec<(PathBuf, Exclusion)>,
+}
+
+impl BuildReport {
+    pub fn filtered_count(&self, reason: Exclusion) -> usize {
+        self.filtered
+            .iter()
+            .filter(|(_, filtered)| *filtered == reason)
+            .count()
+    }
+}
+
+/// Collects paths left out while building or updating. Only failures
+/// are counted unless recording, so plain builds keep nothing.
+#[derive(Default)]
+struct Exclusions {
+    recording: bool,
+    failures: AtomicUsize,
+    report: Mutex<BuildReport>,
+}
+
+impl Exclusions {
+    fn recording() -> Self {
+        Exclusions {
+            recording: true,
+            ..Exclusions::default()
+        }
+    }
+
+    fn filter(&self, path: &Path, reason: Exclusion) {
+        if self.recording {
+            let mut report = self.report.lock().unwrap();
+            report.filtered.push((path.to_owned(), reason));
+        }
+    }
+
+    fn unreadable(&self, path: &Path, error: String) {
+        if self.recording {
+            let mut report = self.report.lock().unwrap();
+            report.unreadable.push((path.to_owned(), error));
+        }
+    }
+
+    fn unscannable(&self, path: &Path, error: String) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        if self.recording {
+            let mut report = self.report.lock().unwrap();
+            report.unscannable.push((path.to_owned(), error));
+        }
+    }
+
+    fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    fn into_report(self) -> BuildReport {
+        let mut report = self.report.into_inner().unwrap();
+        report.unreadable.sort();
+        report.unscannable.sort();
+        report.filtered.sort_by(|a, b| a.0.cmp(&b.0));
+        report
+    }
+}
+
+/// How duplicates would be removed, see `ResourceIndex::simulate_dedup`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupStrategy {
+    /// Every copy but the surviving one is deleted.
+    Delete,
+    /// Every copy is replaced by a hardlink to the surviving one.
+    Hardlink,
+}
+
+/// Outcome of deduplicating the index, see
+/// `ResourceIndex::simulate_dedup`. Paths are sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Bytes no longer taken on the disk, hardlinks taking none.
+    pub freed_bytes: u64,
+    pub removed: Vec<CanonicalPathBuf>,
+    pub hardlinked: Vec<CanonicalPathBuf>,
+    /// The copy kept for every duplicated content.
+    pub survivors: BTreeMap<ResourceId, CanonicalPathBuf>,
+}
+
+/// Calendar day in UTC, see `ResourceIndex::group_by_day`. Ordered
+/// and formatted like chrono's `NaiveDate`, which it stands in for
+/// until chrono can be depended on, e.g. `NaiveDate::from_ymd_opt`
+/// converts it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CalendarDay {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl CalendarDay {
+    pub fn of(time: SystemTime) -> Self {
+        let seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(err) => {
+                let before = err.duration();
+                -(before.as_secs() as i64)
+                    - i64::from(before.subsec_nanos() > 0)
+            }
+        };
+        // days to civil date, after Howard Hinnant's algorithm
+        let days = seconds.div_euclid(86_400) + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460
+            + day_of_era / 36_524
+            - day_of_era / 146_096)
+            / 365;
+        let day_of_year = day_of_era
+            - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
         } else {
-            collisions.insert(id, 2);
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        CalendarDay {
+            year: year as i32,
+            month: month as u8,
+            day: day as u8,
         }
-    } else {
-        ids.insert(id.clone());
     }
 }
 
-fn is_hidden(entry: &DirEntry) -> bool {
-    entry
-        .file_name()
-        .to_str()
-        .map(|s| s.starts_with("."))
-        .unwrap_or(false)
+/// ISO 8601, e.g. `2000-02-29`.
+impl std::fmt::Display for CalendarDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Content-level differences between two directories, see
+/// `ResourceIndex::compare_dirs`. Paths are relative and sorted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DirComparison {
+    /// Paths in the first directory whose content the second lacks.
+    pub only_in_a: Vec<PathBuf>,
+    /// Paths in the second directory whose content the first lacks.
+    pub only_in_b: Vec<PathBuf>,
+    /// Paths in the first directory whose content is also
+    /// in the second one, under the same or another path.
+    pub same: Vec<PathBuf>,
+    /// Paths present in both directories with different content.
+    pub different: Vec<PathBuf>,
+}
+
+/// Files to send to a remote holding some of the content, see
+/// `ResourceIndex::transfer_plan`. Paths are sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TransferPlan {
+    /// One path per id the remote lacks, with its size.
+    pub send: Vec<(CanonicalPathBuf, u64)>,
+    /// Paths whose content the remote has or receives through `send`,
+    /// only their location needs to be transferred.
+    pub moves: Vec<CanonicalPathBuf>,
+    /// Total size of the files to send.
+    pub bytes: u64,
+}
+
+/// Content held by the index and a remote, see
+/// `ResourceIndex::sync_status`. Ids are sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub only_here: Vec<ResourceId>,
+    pub only_there: Vec<ResourceId>,
+    pub in_both: Vec<ResourceId>,
+    /// Total size of the content only here, i.e. to upload.
+    pub only_here_bytes: u64,
+}
+
+/// Files of a directory and their size, see `directory_stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DirStats {
+    /// Files located directly in the directory.
+    pub files: usize,
+    pub size: u64,
+    /// Files in the directory and all its subdirectories.
+    pub total_files: usize,
+    pub total_size: u64,
+}
+
+/// Attribute to sort resources by, see `ResourceIndex::sorted_by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Path(SortOrder),
+    /// File name, compared ignoring case.
+    Name(SortOrder),
+    Size(SortOrder),
+    Modified(SortOrder),
+    /// Extension, compared ignoring case. Files without
+    /// extension come first in ascending order.
+    Extension(SortOrder),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl SortKey {
+    fn compare(self, a: &Resource, b: &Resource) -> std::cmp::Ordering {
+        let folded = |name: &Option<OsString>| {
+            name.as_ref()
+                .map(|name| name.to_string_lossy().to_lowercase())
+        };
+        let (ordering, order) = match self {
+            SortKey::Path(order) => (a.path.cmp(&b.path), order),
+            SortKey::Name(order) => {
+                (folded(&a.meta.name).cmp(&folded(&b.meta.name)), order)
+            }
+            SortKey::Size(order) => (a.meta.size.cmp(&b.meta.size), order),
+            SortKey::Modified(order) => {
+                (a.meta.modified.cmp(&b.meta.modified), order)
+            }
+            SortKey::Extension(order) => (
+                folded(&a.meta.extension).cmp(&folded(&b.meta.extension)),
+                order,
+            ),
+        };
+        match order {
+            SortOrder::Ascending => ordering,
+            SortOrder::Descending => ordering.reverse(),
+        }
+    }
+}
+
+/// What building an index would take, see `ResourceIndex::estimate`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BuildEstimate {
+    pub files: usize,
+    /// Bytes to be hashed.
+    pub bytes: u64,
+}
+
+/// Complete state of an index at some moment, see
+/// `ResourceIndex::checkpoint`.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    path2meta: HashMap<CanonicalPathBuf, ResourceMeta>,
+    collisions: HashMap<ResourceId, usize>,
+    ids: HashSet<ResourceId>,
+    roots: Vec<IndexRoot>,
+    options: IndexOptions,
+    events: VecDeque<IndexEvent>,
+    streams: HashMap<String, ResourceId>,
+    suspicious: HashSet<CanonicalPathBuf>,
+    updated: Option<SystemTime>,
+    scan_errors: usize,
+    tags: HashMap<ResourceId, HashSet<String>>,
+}
+
+/// What to do with a group of duplicates, see
+/// `ResourceIndex::resolve_collisions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CollisionResolution {
+    KeepAll,
+    /// Removes every other copy.
+    KeepOnly(CanonicalPathBuf),
+    /// Removes the given copies.
+    Delete(Vec<CanonicalPathBuf>),
+}
+
+/// Ids of a file before and after its content was replaced,
+/// see `ResourceIndex::replace_content`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentChange {
+    pub old: ResourceId,
+    pub new: ResourceId,
+}
+
+impl ContentChange {
+    pub fn changed(&self) -> bool {
+        self.old != self.new
+    }
+}
+
+/// Paths which disappeared or changed, and freshly scanned
+/// metadata of changed and new paths.
+struct PendingChanges {
+    removed: Vec<CanonicalPathBuf>,
+    scanned: HashMap<CanonicalPathBuf, ResourceMeta>,
+    /// Files which became directories and directories
+    /// which became files.
+    retyped: HashSet<PathBuf>,
+    /// Files which couldn't be scanned.
+    errors: usize,
+}
+
+impl ResourceIndex {
+    /// The first of the roots.
+    pub fn root(&self) -> &Path {
+        &self.roots[0].path
+    }
+
+    pub fn roots(&self) -> &[IndexRoot] {
+        &self.roots
+    }
+
+    /// Options the index was built with, restored by `load`
+    /// except for callbacks.
+    pub fn options(&self) -> &IndexOptions {
+        &self.options
+    }
+
+    /// Routes log messages of later updates to the sink,
+    /// see `IndexOptions::logger`.
+    pub fn with_logger<F>(mut self, sink: F) -> Self
+    where
+        F: Fn(Level, &str) + Send + Sync + 'static,
+    {
+        self.options.logger = Some(LogSink::new(sink));
+        self
+    }
+
+    /// Distinct contents present in the index.
+    pub fn ids(&self) -> &HashSet<ResourceId> {
+        &self.ids
+    }
+
+    /// Algorithm of the ids computed by the index, ids of
+    /// different algorithms are never equal.
+    pub fn algorithm(&self) -> AlgorithmId {
+        match self.options.partial_hash {
+            Some(prefix_len) => AlgorithmId::Crc32Prefix(prefix_len),
+            None => AlgorithmId::Crc32,
+        }
+    }
+
+    /// Number of indexed paths, same as `path_count`.
+    pub fn size(&self) -> usize {
+        self.path_count()
+    }
+
+    /// Number of indexed paths, copies of the same content counted apart.
+    pub fn path_count(&self) -> usize {
+        self.path2meta.len()
+    }
+
+    /// Number of distinct contents, lower than `path_count`
+    /// in presence of collisions.
+    pub fn content_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Paths of every content present more than once, sorted.
+    /// With `collapse_hardlinks`, only the first path of each
+    /// hardlinked file is listed.
+    pub fn duplicates(&self) -> HashMap<ResourceId, Vec<&CanonicalPathBuf>> {
+        let mut duplicates: HashMap<ResourceId, Vec<&CanonicalPathBuf>> =
+            HashMap::with_capacity(self.collisions.len());
+        for (path, meta) in self.path2meta.iter() {
+            if self.collisions.contains_key(&meta.id) {
+                duplicates
+                    .entry(meta.id.clone())
+                    .or_default()
+                    .push(path);
+            }
+        }
+        for paths in duplicates.values_mut() {
+            paths.sort();
+            if self.options.collapse_hardlinks {
+                let mut nodes = HashSet::new();
+                paths.retain(|path| match self.path2meta[*path].inode {
+                    Some(node) => nodes.insert(node),
+                    None => true,
+                });
+            }
+        }
+        duplicates.retain(|_, paths| paths.len() > 1);
+        duplicates
+    }
+
+    /// Content present at exactly `n` paths, e.g. `2` for files with a
+    /// single backup. Paths are sorted, groups by their first path.
+    pub fn resources_with_copy_count(
+        &self,
+        n: usize,
+    ) -> Vec<(ResourceId, Vec<CanonicalPathBuf>)> {
+        let mut groups: HashMap<&ResourceId, Vec<CanonicalPathBuf>> =
+            HashMap::new();
+        for (path, meta) in self.path2meta.iter() {
+            if self.copies(&meta.id) == n {
+                groups
+                    .entry(&meta.id)
+                    .or_default()
+                    .push(path.clone());
+            }
+        }
+
+        let mut groups: Vec<(ResourceId, Vec<CanonicalPathBuf>)> = groups
+            .into_iter()
+            .map(|(id, mut paths)| {
+                paths.sort();
+                (id.clone(), paths)
+            })
+            .collect();
+        groups.sort_by(|(_, a), (_, b)| a[0].cmp(&b[0]));
+        groups
+    }
+
+    /// Duplicates whose copies don't all have the same extension,
+    /// compared ignoring case, e.g. a `.jpg` also saved as `.png`.
+    /// A missing extension is different from any other one.
+    pub fn duplicates_with_differing_extensions(
+        &self,
+    ) -> HashMap<ResourceId, Vec<&CanonicalPathBuf>> {
+        let mut duplicates = self.duplicates();
+        duplicates.retain(|_, paths| {
+            let extensions: HashSet<Option<String>> = paths
+                .iter()
+                .map(|path| {
+                    self.path2meta[*path]
+                        .extension
+                        .as_ref()
+                        .map(|extension| {
+                            extension.to_string_lossy().to_lowercase()
+                        })
+                })
+                .collect();
+            extensions.len() > 1
+        });
+        duplicates
+    }
+
+    /// Same as `duplicates`, with paths cloned, so the result
+    /// can outlive the borrow, e.g. to be sent to another thread.
+    pub fn duplicates_owned(
+        &self,
+    ) -> HashMap<ResourceId, Vec<CanonicalPathBuf>> {
+        self.duplicates()
+            .into_iter()
+            .map(|(id, paths)| (id, paths.into_iter().cloned().collect()))
+            .collect()
+    }
+
+    /// Duplicates grouped by the deepest directory containing all
+    /// their copies: the shared parent when copies are siblings,
+    /// otherwise the closest common ancestor of their parents.
+    pub fn duplicates_by_directory(
+        &self,
+    ) -> HashMap<PathBuf, Vec<(ResourceId, Vec<&CanonicalPathBuf>)>> {
+        let mut groups: HashMap<
+            PathBuf,
+            Vec<(ResourceId, Vec<&CanonicalPathBuf>)>,
+        > = HashMap::new();
+        for (id, paths) in self.duplicates() {
+            let directory = common_ancestor(
+                paths
+                    .iter()
+                    .filter_map(|path| path.as_path().parent()),
+            );
+            groups
+                .entry(directory)
+                .or_default()
+                .push((id, paths));
+        }
+        groups
+    }
+
+    /// Duplicates grouped by type of content: `image`, `video`, `audio`,
+    /// `text`, `document` or `archive`, guessed from the extension of
+    /// the first copy. Other extensions are used as the key as is,
+    /// files without extension are grouped under `unknown`.
+    pub fn duplicates_by_type(
+        &self,
+    ) -> HashMap<String, Vec<(ResourceId, Vec<CanonicalPathBuf>)>> {
+        let mut groups: HashMap<
+            String,
+            Vec<(ResourceId, Vec<CanonicalPathBuf>)>,
+        > = HashMap::new();
+        for (id, paths) in self.duplicates() {
+            let category = content_category(&self.path2meta[paths[0]]);
+            groups
+                .entry(category)
+                .or_default()
+                .push((id, paths.into_iter().cloned().collect()));
+        }
+        groups
+    }
+
+    /// Duplicated content paired with the bytes reclaimable by keeping
+    /// a single copy, `(copies - 1) * size`, biggest waste first.
+    pub fn duplicate_waste_ranked(&self) -> Vec<(ResourceId, u64)> {
+        let mut ranked: Vec<(ResourceId, u64)> = self
+            .duplicates()
+            .into_iter()
+            .map(|(id, paths)| {
+                let copies = paths.len() as u64;
+                (id, (copies - 1) * self.path2meta[paths[0]].size)
+            })
+            .collect();
+        ranked.sort_by(|(a, a_waste), (b, b_waste)| {
+            b_waste.cmp(a_waste).then_with(|| a.cmp(b))
+        });
+        ranked
+    }
+
+    /// Text files whose words are similar to the ones of the content,
+    /// e.g. other drafts of a document, paired with their estimated
+    /// Jaccard similarity of word triples, at least `threshold`.
+    /// Most similar first, copies of the content itself excluded.
+    #[cfg(feature = "similarity")]
+    pub fn similar_documents(
+        &self,
+        id: &ResourceId,
+        threshold: f64,
+    ) -> Vec<(&CanonicalPathBuf, f64)> {
+        let signature = self
+            .path2meta
+            .values()
+            .find(|meta| &meta.id == id)
+            .and_then(|meta| meta.minhash.as_ref());
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return vec![],
+        };
+
+        let mut similar: Vec<(&CanonicalPathBuf, f64)> = self
+            .path2meta
+            .iter()
+            .filter(|(_, meta)| &meta.id != id)
+            .filter_map(|(path, meta)| {
+                let other = meta.minhash.as_ref()?;
+                let similarity =
+                    crate::similarity::similarity(signature, other);
+                (similarity >= threshold).then_some((path, similarity))
+            })
+            .collect();
+        similar.sort_by(|(a, a_similarity), (b, b_similarity)| {
+            b_similarity
+                .total_cmp(a_similarity)
+                .then_with(|| a.cmp(b))
+        });
+        similar
+    }
+
+    /// What deduplicating with the strategy would do, without touching
+    /// the disk or the index. The first path of every group survives,
+    /// as with `gather_duplicates_into`. Copies which are hardlinks of
+    /// the survivor free nothing and aren't relinked; hardlinks are
+    /// only known on Unix.
+    pub fn simulate_dedup(&self, strategy: DedupStrategy) -> DedupReport {
+        let mut groups: HashMap<&ResourceId, Vec<&CanonicalPathBuf>> =
+            HashMap::new();
+        for (path, meta) in self.path2meta.iter() {
+            if self.collisions.contains_key(&meta.id) {
+                groups.entry(&meta.id).or_default().push(path);
+            }
+        }
+
+        let mut report = DedupReport::default();
+        for (id, mut paths) in groups {
+            paths.sort();
+            let survivor = paths[0];
+            let size = self.path2meta[survivor].size;
+            let survivor_node = self.path2meta[survivor].inode;
+            let mut freed: HashSet<FileNode> = HashSet::new();
+            for &path in paths.iter().skip(1) {
+                let node = self.path2meta[path].inode;
+                let linked = node.is_some() && node == survivor_node;
+                // every other inode is freed once, however many links
+                if !linked && node.is_none_or(|node| freed.insert(node)) {
+                    report.freed_bytes += size;
+                }
+                match strategy {
+                    DedupStrategy::Delete => report.removed.push(path.clone()),
+                    DedupStrategy::Hardlink if !linked => {
+                        report.hardlinked.push(path.clone())
+                    }
+                    DedupStrategy::Hardlink => {}
+                }
+            }
+            report
+                .survivors
+                .insert(id.clone(), survivor.clone());
+        }
+        report.removed.sort();
+        report.hardlinked.sort();
+        report
+    }
+
+    /// Likely leftovers of interrupted copies: files whose whole content
+    /// is the beginning of a bigger indexed file, paired with the
+    /// biggest such file, sorted. Only the first bytes of every file
+    /// are read to find candidates, files shorter than 4 KiB aren't
+    /// considered. Requires full ids, an index built with
+    /// `IndexOptions::partial_hash` has no partial copies.
+    pub fn partial_copies(&self) -> Vec<(CanonicalPathBuf, CanonicalPathBuf)> {
+        if self.options.partial_hash.is_some() {
+            return vec![];
+        }
+
+        let mut by_head: HashMap<ResourceId, Vec<(&CanonicalPathBuf, u64)>> =
+            HashMap::new();
+        for (path, meta) in self.path2meta.iter() {
+            if meta.file_type != ResourceFileType::File
+                || meta.size < PARTIAL_COPY_PROBE
+            {
+                continue;
+            }
+            match prefix_id(path.as_path(), PARTIAL_COPY_PROBE) {
+                Ok(head) => by_head
+                    .entry(head)
+                    .or_default()
+                    .push((path, meta.size)),
+                Err(msg) => {
+                    log::error!("Couldn't read {}: {}", path.display(), msg)
+                }
+            }
+        }
+
+        let mut pairs = vec![];
+        for mut files in by_head.into_values() {
+            // the biggest complete file is found first
+            files.sort_by(|(a, a_size), (b, b_size)| {
+                b_size.cmp(a_size).then_with(|| a.cmp(b))
+            });
+            for (i, (partial, size)) in files.iter().enumerate() {
+                let id = &self.path2meta[*partial].id;
+                let complete = files[..i]
+                    .iter()
+                    .filter(|(_, complete_size)| complete_size > size)
+                    .find(|(complete, _)| {
+                        prefix_id(complete.as_path(), *size)
+                            .is_ok_and(|head| &head == id)
+                    });
+                if let Some((complete, _)) = complete {
+                    pairs.push(((*partial).clone(), (*complete).clone()));
+                }
+            }
+        }
+        pairs.sort();
+        pairs
+    }
+
+    /// Paths of the content matching a glob, relative to the roots:
+    /// `?` stands for any character but `/`, `*` for any number of them
+    /// and `**` also spans directories, e.g. `photos/**/*.jpg`.
+    pub fn paths_by_id_matching(
+        &self,
+        id: &ResourceId,
+        glob: &str,
+    ) -> Vec<&CanonicalPathBuf> {
+        let roots = self.canonical_roots();
+        let glob: Vec<char> = glob.chars().collect();
+
+        let mut paths: Vec<&CanonicalPathBuf> = self
+            .path2meta
+            .iter()
+            .filter(|(path, meta)| {
+                let relative: Vec<char> =
+                    portable_relative_path(&roots, path.as_path())
+                        .chars()
+                        .collect();
+                &meta.id == id && glob_matches(&glob, &relative)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    /// Statistics of every directory containing indexed files,
+    /// including the roots and directories with subdirectories only.
+    pub fn directory_stats(&self) -> HashMap<PathBuf, DirStats> {
+        let roots: HashSet<PathBuf> =
+            self.canonical_roots().into_iter().collect();
+
+        let mut stats: HashMap<PathBuf, DirStats> = HashMap::new();
+        for (path, meta) in self.path2meta.iter() {
+            let parent = match path.as_path().parent() {
+                Some(parent) => parent,
+                None => continue,
+            };
+            let immediate = stats.entry(parent.to_path_buf()).or_default();
+            immediate.files += 1;
+            immediate.size += meta.size;
+
+            for dir in parent.ancestors() {
+                let recursive = stats.entry(dir.to_path_buf()).or_default();
+                recursive.total_files += 1;
+                recursive.total_size += meta.size;
+                if roots.contains(dir) {
+                    break;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Up to `n` directories using the most space, subdirectories
+    /// included, biggest first.
+    pub fn largest_directories(&self, n: usize) -> Vec<(PathBuf, DirStats)> {
+        let mut stats: Vec<(PathBuf, DirStats)> =
+            self.directory_stats().into_iter().collect();
+        stats.sort_by(|(a, a_stats), (b, b_stats)| {
+            b_stats
+                .total_size
+                .cmp(&a_stats.total_size)
+                .then_with(|| a.cmp(b))
+        });
+        stats.truncate(n);
+        stats
+    }
+
+    /// When the index was last built or updated, `None` if it
+    /// was loaded and hasn't been updated since.
+    pub fn last_updated(&self) -> Option<SystemTime> {
+        self.updated
+    }
+
+    /// Number of files which couldn't be scanned by the last build
+    /// or update, they are missing from the index.
+    pub fn scan_errors(&self) -> usize {
+        self.scan_errors
+    }
+
+    /// Small document describing the health of the index, e.g. for
+    /// dashboards. Its keys are stable: `files`, `unique`,
+    /// `total_bytes`, `duplicate_bytes`, `top_extensions` (up to 10
+    /// `[extension, count]` pairs, most frequent first),
+    /// `last_updated` (seconds since the epoch or `null`)
+    /// and `errors`.
+    pub fn summary_json(&self) -> serde_json::Value {
+        let mut extensions: HashMap<String, usize> = HashMap::new();
+        for path in self.path2meta.keys() {
+            let extension = path
+                .as_path()
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            *extensions.entry(extension).or_default() += 1;
+        }
+        let mut extensions: Vec<(String, usize)> =
+            extensions.into_iter().collect();
+        extensions.sort_by(|(a, a_count), (b, b_count)| {
+            b_count.cmp(a_count).then_with(|| a.cmp(b))
+        });
+        extensions.truncate(10);
+
+        let last_updated = self.updated.and_then(|time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .ok()
+                .map(|since| since.as_secs())
+        });
+
+        serde_json::json!({
+            "files": self.path_count(),
+            "unique": self.content_count(),
+            "total_bytes": self
+                .path2meta
+                .values()
+                .map(|meta| meta.size)
+                .sum::<u64>(),
+            "duplicate_bytes": self
+                .duplicate_waste_ranked()
+                .into_iter()
+                .map(|(_, waste)| waste)
+                .sum::<u64>(),
+            "top_extensions": extensions,
+            "last_updated": last_updated,
+            "errors": self.scan_errors,
+        })
+    }
+
+    /// Entries matching the predicate, filtered lazily as the iterator
+    /// is advanced, in no particular order.
+    pub fn filter_iter<'a, F>(
+        &'a self,
+        predicate: F,
+    ) -> impl Iterator<Item = (&'a CanonicalPathBuf, &'a ResourceMeta)>
+    where
+        F: Fn(&CanonicalPathBuf, &ResourceMeta) -> bool + 'a,
+    {
+        self.path2meta
+            .iter()
+            .filter(move |(path, meta)| predicate(path, meta))
+    }
+
+    /// Up to `n` entries picked at random by reservoir sampling, the
+    /// same ones for the same seed and content of the index.
+    pub fn sample(
+        &self,
+        n: usize,
+        seed: u64,
+    ) -> Vec<(&CanonicalPathBuf, &ResourceMeta)> {
+        // the map iterates in a different order in every process
+        let mut entries: Vec<(&CanonicalPathBuf, &ResourceMeta)> =
+            self.path2meta.iter().collect();
+        entries.sort_by_key(|(path, _)| *path);
+
+        let mut state = seed;
+        let mut reservoir = Vec::with_capacity(n.min(entries.len()));
+        for (i, entry) in entries.into_iter().enumerate() {
+            if i < n {
+                reservoir.push(entry);
+                continue;
+            }
+            let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+            if j < n {
+                reservoir[j] = entry;
+            }
+        }
+        reservoir
+    }
+
+    /// Every resource, sorted by the first key, then by the next one
+    /// among equal resources, and so on. Resources equal by all
+    /// the keys are sorted by path.
+    pub fn sorted_by(&self, keys: &[SortKey]) -> Vec<Resource> {
+        let mut resources: Vec<Resource> = self
+            .path2meta
+            .iter()
+            .map(|(path, meta)| Resource {
+                path: path.clone(),
+                meta: meta.clone(),
+            })
+            .collect();
+        resources.sort_by(|a, b| {
+            keys.iter()
+                .map(|key| key.compare(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a.path.cmp(&b.path))
+        });
+        resources
+    }
+
+    /// Entries located directly in the directory, not in its
+    /// subdirectories, sorted by path. Empty if the directory
+    /// can't be canonicalized.
+    pub fn entries_in_dir<P: AsRef<Path>>(
+        &self,
+        dir: P,
+    ) -> Vec<(&CanonicalPathBuf, &ResourceMeta)> {
+        let dir = match fs::canonicalize(dir) {
+            Ok(dir) => dir,
+            Err(_) => return vec![],
+        };
+        let mut entries: Vec<(&CanonicalPathBuf, &ResourceMeta)> = self
+            .path2meta
+            .iter()
+            .filter(|(path, _)| path.as_path().parent() == Some(dir.as_path()))
+            .collect();
+        entries.sort_by_key(|(path, _)| *path);
+        entries
+    }
+
+    /// Resources grouped by their immediate parent directory,
+    /// directories and resources within them sorted by path. The
+    /// paths are sorted once up front, groups borrow from the index
+    /// and are gathered only as the iterator advances.
+    pub fn group_by_directory(
+        &self,
+    ) -> impl Iterator<Item = DirectoryGroup<'_>> {
+        let mut entries: Vec<(&CanonicalPathBuf, &ResourceMeta)> =
+            self.path2meta.iter().collect();
+        entries.sort_by_key(|(path, _)| (path.as_path().parent(), *path));
+        let mut entries = entries.into_iter().peekable();
+        std::iter::from_fn(move || loop {
+            let first = entries.next()?;
+            let Some(directory) = first.0.as_path().parent() else {
+                continue;
+            };
+            let mut group = vec![first];
+            while let Some(next) = entries
+                .next_if(|(path, _)| path.as_path().parent() == Some(directory))
+            {
+                group.push(next);
+            }
+            return Some((directory, group));
+        })
+    }
+
+    /// Id of the content at the path, `None` if the path
+    /// isn't indexed or can't be canonicalized.
+    pub fn id_at<P: AsRef<Path>>(&self, path: P) -> Option<&ResourceId> {
+        let path = self.key_of(path.as_ref())?;
+        self.path2meta.get(&path).map(|meta| &meta.id)
+    }
+
+    /// Clone of the metadata at the path, `None` if the path
+    /// isn't indexed or can't be canonicalized.
+    pub fn get_meta_owned<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Option<ResourceMeta> {
+        let path = self.key_of(path.as_ref())?;
+        self.path2meta.get(&path).cloned()
+    }
+
+    /// Whether the index holds exactly this entry: the same path
+    /// with the same id, timestamp and other attributes.
+    pub fn entry_matches(
+        &self,
+        path: &CanonicalPathBuf,
+        meta: &ResourceMeta,
+    ) -> bool {
+        self.path2meta.get(path) == Some(meta)
+    }
+
+    /// Entries modified strictly after `when`, according to
+    /// the state of the last build or update.
+    pub fn modified_since(
+        &self,
+        when: SystemTime,
+    ) -> Vec<(&CanonicalPathBuf, &ResourceMeta)> {
+        self.path2meta
+            .iter()
+            .filter(|(_, meta)| meta.modified > when)
+            .collect()
+    }
+
+    /// Paths bucketed by the UTC day they were modified, or created if
+    /// `use_created` is set and the creation time is known. Paths are
+    /// sorted within each day.
+    pub fn group_by_day(
+        &self,
+        use_created: bool,
+    ) -> BTreeMap<CalendarDay, Vec<&CanonicalPathBuf>> {
+        let mut days: BTreeMap<CalendarDay, Vec<&CanonicalPathBuf>> =
+            BTreeMap::new();
+        for (path, meta) in self.path2meta.iter() {
+            let time = match meta.created {
+                Some(created) if use_created => created,
+                _ => meta.modified,
+            };
+            days.entry(CalendarDay::of(time))
+                .or_default()
+                .push(path);
+        }
+        for paths in days.values_mut() {
+            paths.sort();
+        }
+        days
+    }
+
+    /// The entry created first, by its modification time if the creation
+    /// time is unknown. The first path of them if there are several.
+    pub fn oldest(&self) -> Option<(&CanonicalPathBuf, &ResourceMeta)> {
+        self.path2meta
+            .iter()
+            .min_by(|(a, a_meta), (b, b_meta)| {
+                date_of(a_meta)
+                    .cmp(&date_of(b_meta))
+                    .then_with(|| a.cmp(b))
+            })
+    }
+
+    /// The entry created last, by its modification time if the creation
+    /// time is unknown. The first path of them if there are several.
+    pub fn newest(&self) -> Option<(&CanonicalPathBuf, &ResourceMeta)> {
+        self.path2meta
+            .iter()
+            .min_by(|(a, a_meta), (b, b_meta)| {
+                date_of(b_meta)
+                    .cmp(&date_of(a_meta))
+                    .then_with(|| a.cmp(b))
+            })
+    }
+
+    /// Dates of the oldest and the newest entries, see `oldest`.
+    pub fn date_range(&self) -> Option<(SystemTime, SystemTime)> {
+        self.path2meta
+            .values()
+            .map(date_of)
+            .fold(None, |range, date| match range {
+                None => Some((date, date)),
+                Some((min, max)) => Some((min.min(date), max.max(date))),
+            })
+    }
+
+    pub fn build<P: AsRef<Path>>(root_path: P) -> Result<Self, Error> {
+        Self::build_with_options(root_path, IndexOptions::default())
+    }
+
+    pub fn build_with_options<P: AsRef<Path>>(
+        root_path: P,
+        options: IndexOptions,
+    ) -> Result<Self, Error> {
+        Self::build_with_roots(
+            vec![IndexRoot::new(root_path.as_ref())],
+            options,
+        )
+    }
+
+    /// Builds a single index over several roots, each with
+    /// its own extension filter. Collisions span all roots.
+    pub fn build_with_roots(
+        roots: Vec<IndexRoot>,
+        options: IndexOptions,
+    ) -> Result<Self, Error> {
+        Self::build_reusing(roots, options, None, &Exclusions::default())
+    }
+
+    /// Same as `build_with_options`, also reporting every path left
+    /// out of the index and why, e.g. to fix permissions and build
+    /// the unreadable directories again.
+    pub fn build_with_report<P: AsRef<Path>>(
+        root_path: P,
+        options: IndexOptions,
+    ) -> Result<(Self, BuildReport), Error> {
+        let excluded = Exclusions::recording();
+        let index = Self::build_reusing(
+            vec![IndexRoot::new(root_path.as_ref())],
+            options,
+            None,
+            &excluded,
+        )?;
+        Ok((index, excluded.into_report()))
+    }
+
+    /// Same as `build`, but ids of files unchanged since a previous
+    /// build with the same cache are reused instead of re-hashing the
+    /// files. Files are recognized by device and inode, so nothing is
+    /// cached on other platforms than Unix. The cache is created if
+    /// missing and updated with the files of the new index.
+    pub fn build_with_cache<P, C>(
+        root_path: P,
+        cache_path: C,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        C: AsRef<Path>,
+    {
+        let cache_path = cache_path.as_ref();
+        let mut cache = ChecksumCache::load(cache_path)?;
+        let index = Self::build_reusing(
+            vec![IndexRoot::new(root_path.as_ref())],
+            IndexOptions::default(),
+            Some(&cache),
+            &Exclusions::default(),
+        )?;
+        cache.extend(index.path2meta.values());
+        cache.store(cache_path)?;
+        Ok(index)
+    }
+
+    fn build_reusing(
+        roots: Vec<IndexRoot>,
+        options: IndexOptions,
+        cache: Option<&ChecksumCache>,
+        excluded: &Exclusions,
+    ) -> Result<Self, Error> {
+        report!(options, Level::Info, "Creating the index from scratch");
+        if roots.is_empty() {
+            return Err(Error::msg("At least one root is required"));
+        }
+
+        let walked = SystemTime::now();
+        let mut paths = HashMap::new();
+        let mut dirs = DirTimes::new();
+        for root in roots.iter() {
+            paths.extend(root.discover(&options, &mut dirs, excluded)?);
+        }
+        let metadata = scan_metadata(paths, &options, cache, excluded);
+
+        let mut index = Self::assemble(roots, options, metadata);
+        if let Some(classifier) = index.options.classifier.clone() {
+            let paths: Vec<CanonicalPathBuf> =
+                index.path2meta.keys().cloned().collect();
+            for path in paths.iter() {
+                index.classify(&classifier, path);
+            }
+        }
+        index.dir_times = trusted_dirs(dirs, walked);
+        index.updated = Some(SystemTime::now());
+        index.scan_errors = excluded.failures();
+        report!(index.options, Level::Info, "Index built");
+        Ok(index)
+    }
+
+    /// How many files and bytes `build_with_options` would hash, found
+    /// by discovering the files without reading any of them. Files with
+    /// blocked content can't be told apart yet and are counted in.
+    pub fn estimate<P: AsRef<Path>>(
+        root_path: P,
+        options: &IndexOptions,
+    ) -> Result<BuildEstimate, Error> {
+        let mut estimate = BuildEstimate::default();
+        for (path, entry) in discover_paths(root_path, options, None)? {
+            // links are hashed as themselves unless followed
+            let metadata =
+                if entry.path_is_symlink() && !entry.file_type().is_symlink() {
+                    fs::metadata(&path)
+                } else {
+                    entry.metadata().map_err(Into::into)
+                };
+            match metadata {
+                Ok(metadata)
+                    if metadata.len() > 0
+                        || options.empty_file_handling
+                            != EmptyHandling::Skip =>
+                {
+                    estimate.files += 1;
+                    estimate.bytes += metadata.len();
+                }
+                Ok(_) => {}
+                Err(msg) => log::error!(
+                    "Couldn't retrieve metadata for {}: {}",
+                    path.display(),
+                    msg
+                ),
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Fast first pass identifying files by size and the first
+    /// `prefix_len` bytes, see `IndexOptions::partial_hash`.
+    /// Collisions are only candidates for duplicates.
+    pub fn build_partial<P: AsRef<Path>>(
+        root_path: P,
+        prefix_len: u64,
+    ) -> Result<Self, Error> {
+        Self::build_with_options(
+            root_path,
+            IndexOptions {
+                partial_hash: Some(prefix_len),
+                ..IndexOptions::default()
+            },
+        )
+    }
+
+    /// Indexes both directories and tells how their content differs.
+    pub fn compare_dirs<P: AsRef<Path>>(
+        a: P,
+        b: P,
+    ) -> Result<DirComparison, Error> {
+        // paths are indexed canonicalized, so must be the roots
+        let a = Self::build(CanonicalPathBuf::canonicalize(a)?)?;
+        let b = Self::build(CanonicalPathBuf::canonicalize(b)?)?;
+        let relative = |index: &Self| -> HashMap<PathBuf, ResourceId> {
+            index
+                .path2meta
+                .iter()
+                .filter_map(|(path, meta)| {
+                    let path =
+                        path.as_path().strip_prefix(index.root()).ok()?;
+                    Some((path.to_path_buf(), meta.id.clone()))
+                })
+                .collect()
+        };
+        let (a_paths, b_paths) = (relative(&a), relative(&b));
+
+        let mut comparison = DirComparison::default();
+        for (path, id) in a_paths.iter() {
+            match b_paths.get(path) {
+                Some(other) if other != id => {
+                    comparison.different.push(path.clone())
+                }
+                _ if b.ids.contains(id) => comparison.same.push(path.clone()),
+                _ => comparison.only_in_a.push(path.clone()),
+            }
+        }
+        for (path, id) in b_paths.iter() {
+            if !a_paths.contains_key(path) && !a.ids.contains(id) {
+                comparison.only_in_b.push(path.clone());
+            }
+        }
+
+        comparison.only_in_a.sort();
+        comparison.only_in_b.sort();
+        comparison.same.sort();
+        comparison.different.sort();
+        Ok(comparison)
+    }
+
+    /// Compares the content of the index with the ids of a remote,
+    /// e.g. read with `import_ids`.
+    pub fn sync_status(&self, other_ids: &HashSet<ResourceId>) -> SyncStatus {
+        let mut status = SyncStatus::default();
+        for id in self.ids.iter() {
+            if other_ids.contains(id) {
+                status.in_both.push(id.clone());
+            } else {
+                status.only_here_bytes += id.file_size();
+                status.only_here.push(id.clone());
+            }
+        }
+        status.only_there = other_ids.difference(&self.ids).cloned().collect();
+
+        status.only_here.sort();
+        status.only_there.sort();
+        status.in_both.sort();
+        status
+    }
+
+    /// Plans sending the index to a remote holding `remote_ids`, e.g.
+    /// read with `import_ids`. Content the remote lacks is sent once,
+    /// from the first of its paths, all other paths are moves.
+    pub fn transfer_plan(
+        &self,
+        remote_ids: &HashSet<ResourceId>,
+    ) -> TransferPlan {
+        let mut paths: Vec<(&CanonicalPathBuf, &ResourceMeta)> =
+            self.path2meta.iter().collect();
+        paths.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut plan = TransferPlan::default();
+        let mut sent = HashSet::new();
+        for (path, meta) in paths {
+            if remote_ids.contains(&meta.id) || !sent.insert(&meta.id) {
+                plan.moves.push(path.clone());
+            } else {
+                plan.send
+                    .push((path.clone(), meta.id.file_size()));
+                plan.bytes += meta.id.file_size();
+            }
+        }
+        plan
+    }
+
+    /// Builds the index out of newline-delimited paths, e.g. piped
+    /// from `find`. Relative paths are resolved against the root,
+    /// missing or unreadable paths are logged and skipped.
+    pub fn build_from_paths<R: BufRead>(
+        reader: R,
+        root: PathBuf,
+    ) -> Result<Self, Error> {
+        log::info!("Creating the index from the given paths");
+
+        let mut entries = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            let path = root.join(&line);
+            match path_entry(&path) {
+                Ok((path, entry)) => {
+                    entries.insert(path, entry);
+                }
+                Err(msg) => {
+                    log::error!("Couldn't index {}: {}", path.display(), msg)
+                }
+            }
+        }
+
+        let options = IndexOptions::default();
+        let excluded = Exclusions::default();
+        let metadata = scan_metadata(entries, &options, None, &excluded);
+
+        let mut index =
+            Self::assemble(vec![IndexRoot::new(root)], options, metadata);
+        index.updated = Some(SystemTime::now());
+        index.scan_errors = excluded.failures();
+        log::info!("Index built");
+        Ok(index)
+    }
+
+    pub(crate) fn assemble<I>(
+        roots: Vec<IndexRoot>,
+        options: IndexOptions,
+        metadata: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = (CanonicalPathBuf, ResourceMeta)>,
+    {
+        let mut index = ResourceIndex {
+            path2meta: HashMap::new(),
+            collisions: HashMap::new(),
+            ids: HashSet::new(),
+            roots,
+            options,
+            events: VecDeque::new(),
+            streams: HashMap::new(),
+            suspicious: HashSet::new(),
+            updated: None,
+            scan_errors: 0,
+            tags: HashMap::new(),
+            dirty: AtomicBool::new(true),
+            dir_times: DirTimes::new(),
+            on_update: Vec::new(),
+            next_hook: 0,
+            generation: 0,
+            checkpoint: None,
+        };
+
+        for (path, meta) in metadata {
+            add_meta(
+                path,
+                meta,
+                &mut index.path2meta,
+                &mut index.collisions,
+                &mut index.ids,
+            );
+        }
+
+        index
+    }
+
+    /// Brings the index in sync with the disk. Leaves the index
+    /// untouched and fails with `ArkError::RootMissing` when a root is
+    /// gone, or with `ArkError::MassDeletionBlocked` when
+    /// `IndexOptions::mass_deletion_guard` is exceeded.
+    pub fn update(&mut self) -> Result<IndexUpdate, Error> {
+        self.update_guarded(self.options.mass_deletion_guard)
+    }
+
+    /// Same as `update`, but applies any number of deletions.
+    pub fn update_force(&mut self) -> Result<IndexUpdate, Error> {
+        self.update_guarded(None)
+    }
+
+    /// Same as `update`, but reports only ids of the added paths.
+    /// Update hooks still receive the full metadata.
+    pub fn update_light(&mut self) -> Result<LightUpdate, Error> {
+        self.update().map(IndexUpdate::into_light)
+    }
+
+    fn update_guarded(
+        &mut self,
+        guard: Option<f64>,
+    ) -> Result<IndexUpdate, Error> {
+        report!(self.options, Level::Info, "Updating the index");
+        log::trace!("Known paths:\n{:?}", self.path2meta.keys());
+
+        if self.options.trust_dir_mtimes
+            && !self.options.verify_content_on_update
+            && self.suspicious.is_empty()
+            && self.dirs_unchanged()
+        {
+            report!(self.options, Level::Info, "No directory has changed");
+            self.updated = Some(SystemTime::now());
+            return Ok(IndexUpdate::default());
+        }
+
+        self.check_roots()?;
+        let walked = SystemTime::now();
+        let mut curr_entries = HashMap::new();
+        let mut dirs = DirTimes::new();
+        for root in self.roots.iter() {
+            let excluded = Exclusions::default();
+            curr_entries.extend(root.discover(
+                &self.options,
+                &mut dirs,
+                &excluded,
+            )?);
+        }
+
+        let prev_paths: Paths = self.path2meta.keys().cloned().collect();
+        let pending = self.pending_changes(curr_entries, prev_paths);
+        let update = self.resolve(&pending);
+        if let Some(limit) = guard {
+            let gone = pending
+                .removed
+                .iter()
+                .filter(|path| !pending.scanned.contains_key(*path))
+                .count();
+            let total = self.path2meta.len();
+            if total > 0 && gone as f64 / total as f64 > limit {
+                report!(
+                    self.options,
+                    Level::Error,
+                    "Update would delete {} of {} paths",
+                    gone,
+                    total
+                );
+                return Err(ArkError::MassDeletionBlocked {
+                    gone,
+                    total,
+                    update,
+                }
+                .into());
+            }
+        }
+        self.suspicious.clear();
+        self.updated = Some(SystemTime::now());
+        self.scan_errors = pending.errors;
+        self.apply(&pending, &update);
+        self.dir_times = trusted_dirs(dirs, walked);
+
+        self.notify(&update);
+        Ok(update)
+    }
+
+    /// Whether every directory seen by the last walk is still there,
+    /// with the same modification time.
+    fn dirs_unchanged(&self) -> bool {
+        !self.dir_times.is_empty()
+            && self.dir_times.iter().all(|(dir, time)| {
+                fs::metadata(dir)
+                    .and_then(|metadata| metadata.modified())
+                    .is_ok_and(|modified| modified == *time)
+            })
+    }
+
+    /// Brings the given paths in sync with the disk, e.g. as reported
+    /// by a filesystem watcher, without walking the roots. Missing
+    /// paths are forgotten, others are scanned one by one. When a file
+    /// can't be scanned, it is skipped, unless `transactional` is set:
+    /// then the index is rolled back to its state before the batch
+    /// and the error is returned.
+    pub fn apply_changes<I, P>(
+        &mut self,
+        paths: I,
+        transactional: bool,
+    ) -> Result<IndexUpdate, Error>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let checkpoint = transactional.then(|| self.checkpoint());
+        let mut batch = IndexUpdate::default();
+        for path in paths {
+            let path = path.as_ref();
+            let pending = match self.path_changes(path) {
+                Ok(pending) => pending,
+                Err(err) => match checkpoint {
+                    Some(checkpoint) => {
+                        log::error!("Rolling back the batch");
+                        self.restore(checkpoint);
+                        return Err(err);
+                    }
+                    None => {
+                        log::error!(
+                            "Couldn't apply {}: {}",
+                            path.display(),
+                            err
+                        );
+                        continue;
+                    }
+                },
+            };
+            let update = self.resolve(&pending);
+            self.apply(&pending, &update);
+
+            batch.deleted.extend(update.deleted);
+            batch.added.extend(update.added);
+            for (path, (old, new)) in update.modified_in_place {
+                batch
+                    .modified_in_place
+                    .entry(path)
+                    .and_modify(|(_, latest)| *latest = new.clone())
+                    .or_insert((old, new));
+            }
+        }
+
+        // later changes of the batch may undo earlier ones
+        batch
+            .added
+            .retain(|path, meta| self.path2meta.get(path) == Some(meta));
+        batch
+            .modified_in_place
+            .retain(|_, (old, new)| old != new);
+        let added: HashSet<&ResourceId> = batch
+            .added
+            .values()
+            .map(|meta| &meta.id)
+            .collect();
+        batch
+            .deleted
+            .retain(|id| !self.ids.contains(id) || added.contains(id));
+
+        self.notify(&batch);
+        Ok(batch)
+    }
+
+    /// Whether the path is a link indexed at its own path,
+    /// see `SymlinkMode::HashLinkTarget`.
+    fn is_hashed_link(&self, path: &Path) -> bool {
+        self.options.symlinks == SymlinkMode::HashLinkTarget
+            && fs::symlink_metadata(path)
+                .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    }
+
+    /// Key of the path in `path2meta`: links hashed by their target path
+    /// are kept at their own path, anything else is canonicalized.
+    fn key_of(&self, path: &Path) -> Option<CanonicalPathBuf> {
+        if self.is_hashed_link(path) {
+            link_path(path)
+        } else {
+            CanonicalPathBuf::canonicalize(path).ok()
+        }
+    }
+
+    /// Changes of a single path, as seen by `update`. Files which
+    /// vanished or became empty while skipping empty files are removed.
+    fn path_changes(&self, path: &Path) -> Result<PendingChanges, Error> {
+        let link = self.is_hashed_link(path);
+        // vanished files are found at their canonical directory
+        let key = self
+            .key_of(path)
+            .or_else(|| link_path(path))
+            .ok_or_else(|| {
+                Error::msg(format!("{} can't be resolved", path.display()))
+            })?;
+        let roots = self.canonical_roots();
+        if !roots.iter().any(|root| key.starts_with(root)) {
+            return Err(Error::msg(format!(
+                "{} is outside of the index",
+                path.display()
+            )));
+        }
+
+        let mut removed = vec![];
+        if self.path2meta.contains_key(&key) {
+            removed.push(key.clone());
+        }
+        let mut scanned = HashMap::new();
+        if link || fs::metadata(&key).is_ok_and(|metadata| metadata.is_file()) {
+            let scan = entry_at(&key).and_then(|entry| {
+                ResourceMeta::scan_with_options(
+                    key.clone(),
+                    entry,
+                    &self.options,
+                )
+            });
+            match scan {
+                Ok((key, meta)) => {
+                    if !self.options.blocked_ids.contains(&meta.id) {
+                        scanned.insert(key, meta);
+                    }
+                }
+                Err(err) if is_gone(&err) => {
+                    log::debug!("{} is gone: {}", path.display(), err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(PendingChanges {
+            removed,
+            scanned,
+            retyped: HashSet::new(),
+            errors: 0,
+        })
+    }
+
+    /// Applies the changes, which `resolve` turned into the update.
+    fn apply(&mut self, pending: &PendingChanges, update: &IndexUpdate) {
+        // only a walk tells the state of all directories
+        self.dir_times.clear();
+        if !pending.removed.is_empty() || !pending.scanned.is_empty() {
+            *self.dirty.get_mut() = true;
+        }
+        for path in pending.retyped.iter() {
+            report!(
+                self.options,
+                Level::Info,
+                "Path {} changed its type",
+                path.display()
+            );
+        }
+
+        for path in pending.removed.iter() {
+            if let Some(old) = self.path2meta.get(path).map(|m| m.id.clone()) {
+                let kind = match update.added.get(path) {
+                    Some(meta) => IndexEventKind::Changed {
+                        old,
+                        new: meta.id.clone(),
+                    },
+                    None => IndexEventKind::Deleted(old),
+                };
+                self.record(path, kind);
+            }
+            remove_meta(
+                path,
+                &mut self.path2meta,
+                &mut self.collisions,
+                &mut self.ids,
+            );
+        }
+
+        let removed: HashSet<&CanonicalPathBuf> =
+            pending.removed.iter().collect();
+        for (path, meta) in update.added.iter() {
+            if !removed.contains(path) {
+                self.record(path, IndexEventKind::Added(meta.id.clone()));
+            }
+
+            let retyped = path
+                .as_path()
+                .ancestors()
+                .any(|ancestor| pending.retyped.contains(ancestor));
+            if update.deleted.contains(&meta.id) && !retyped {
+                // emitting the resource as both deleted and added
+                // (renaming a duplicate might remain undetected)
+                log::info!(
+                    "Resource {:?} was moved to {}",
+                    meta.id,
+                    path.display()
+                );
+            }
+
+            add_meta(
+                path.clone(),
+                meta.clone(),
+                &mut self.path2meta,
+                &mut self.collisions,
+                &mut self.ids,
+            );
+        }
+
+        for (old, new) in update.modified_in_place.values() {
+            self.carry_tags(old, new);
+        }
+        if let Some(classifier) = self.options.classifier.clone() {
+            for path in update.added.keys() {
+                self.classify(&classifier, path);
+            }
+        }
+    }
+
+    fn classify(&mut self, classifier: &Classifier, path: &CanonicalPathBuf) {
+        let meta = match self.path2meta.get(path) {
+            Some(meta) => meta,
+            None => return,
+        };
+        let id = meta.id.clone();
+        for tag in (classifier.0)(path, meta) {
+            self.tag(&id, &tag);
+        }
+    }
+
+    fn carry_tags(&mut self, old: &ResourceId, new: &ResourceId) {
+        if !self.options.carry_metadata_on_edit || old == new {
+            return;
+        }
+        let tags = match self.tags.get(old) {
+            Some(tags) if self.ids.contains(old) => tags.clone(),
+            _ => match self.tags.remove(old) {
+                Some(tags) => tags,
+                None => return,
+            },
+        };
+        self.tags
+            .entry(new.clone())
+            .or_default()
+            .extend(tags);
+    }
+
+    /// What `update` would report for the given subdirectory,
+    /// without modifying the index. Changes outside of the
+    /// subdirectory are neither detected nor reported.
+    pub fn preview_update_subtree<P: AsRef<Path>>(
+        &self,
+        subdir: P,
+    ) -> Result<IndexUpdate, Error> {
+        let subdir = CanonicalPathBuf::canonicalize(subdir)?;
+        log::info!("Previewing update of {}", subdir.display());
+
+        // the innermost root containing the subdirectory
+        // decides which files belong to the index
+        let root = self
+            .roots
+            .iter()
+            .filter(|root| match fs::canonicalize(&root.path) {
+                Ok(path) => subdir.starts_with(path),
+                Err(_) => false,
+            })
+            .max_by_key(|root| root.path.components().count())
+            .ok_or_else(|| {
+                Error::msg(format!(
+                    "{} is outside of the index",
+                    subdir.display()
+                ))
+            })?;
+        let curr_entries =
+            discover_paths(&subdir, &self.options, root.extensions.as_ref())?;
+
+        let prev_paths: Paths = self
+            .path2meta
+            .keys()
+            .filter(|path| path.starts_with(&subdir))
+            .cloned()
+            .collect();
+        let pending = self.pending_changes(curr_entries, prev_paths);
+
+        Ok(self.resolve(&pending))
+    }
+
+    /// Compares discovered entries against `prev_paths`, the known
+    /// paths in the same area. Created and updated files are scanned.
+    fn pending_changes(
+        &self,
+        curr_entries: HashMap<CanonicalPathBuf, DirEntry>,
+        prev_paths: Paths,
+    ) -> PendingChanges {
+        //assuming that collections manipulation is
+        // quicker than asking `path.exists()` for every path
+        let curr_paths: Paths = curr_entries.keys().cloned().collect();
+        let preserved_paths: Paths = curr_paths
+            .intersection(&prev_paths)
+            .cloned()
+            .collect();
+
+        let created_paths: HashMap<CanonicalPathBuf, DirEntry> = curr_entries
+            .iter()
+            .filter_map(|(path, entry)| {
+                if !preserved_paths.contains(path.as_canonical_path()) {
+                    Some((path.clone(), entry.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        report!(self.options, Level::Info, "Checking updated paths");
+        let excluded = Exclusions::default();
+        let verify = |path: &CanonicalPathBuf| {
+            self.options.verify_content_on_update
+                || self.suspicious.contains(path)
+        };
+        let mut verified: Paths = HashSet::new();
+        let updated_paths: HashMap<CanonicalPathBuf, DirEntry> = curr_entries
+            .into_iter()
+            .filter(|(path, entry)| {
+                if !preserved_paths.contains(path.as_canonical_path()) {
+                    false
+                } else {
+                    let prev_modified = self.path2meta[path].modified;
+
+                    let result = entry.metadata();
+                    match result {
+                        Err(msg) => {
+                            report!(
+                                self.options,
+                                Level::Error,
+                                "Couldn't retrieve metadata for {}: {}",
+                                &path.display(),
+                                msg
+                            );
+                            false
+                        }
+                        Ok(metadata) => match metadata.modified() {
+                            Err(msg) => {
+                                report!(
+                                    self.options,
+                                    Level::Error,
+                                    "Couldn't retrieve timestamp for {}: {}",
+                                    &path.display(),
+                                    msg
+                                );
+                                false
+                            }
+                            Ok(curr_modified)
+                                if curr_modified > prev_modified =>
+                            {
+                                true
+                            }
+                            Ok(_) if verify(path) => {
+                                verified.insert(path.clone());
+                                true
+                            }
+                            Ok(_) => false,
+                        },
+                    }
+                }
+            })
+            .collect();
+
+        let updated: Vec<CanonicalPathBuf> =
+            updated_paths.keys().cloned().collect();
+        let mut scanned: HashMap<CanonicalPathBuf, ResourceMeta> =
+            scan_metadata(updated_paths, &self.options, None, &excluded);
+        // verified files which kept their content aren't updated
+        verified.retain(|path| match scanned.get(path) {
+            Some(meta) => meta.id == self.path2meta[path].id,
+            None => false,
+        });
+        for path in verified.iter() {
+            log::trace!("Content of {} is verified", path.display());
+            scanned.remove(path);
+        }
+
+        // treating deleted and updated paths as deletions
+        let removed: Vec<CanonicalPathBuf> = prev_paths
+            .difference(&preserved_paths)
+            .cloned()
+            .chain(
+                updated
+                    .into_iter()
+                    .filter(|path| !verified.contains(path)),
+            )
+            .collect();
+        let retyped = retyped_paths(&removed, created_paths.keys());
+
+        report!(self.options, Level::Info, "The same for new paths");
+        scanned.extend(scan_metadata(
+            created_paths,
+            &self.options,
+            None,
+            &excluded,
+        ));
+
+        PendingChanges {
+            removed,
+            scanned,
+            retyped,
+            errors: excluded.failures(),
+        }
+    }
+
+    /// The update resulting from applying the changes to the index.
+    fn resolve(&self, pending: &PendingChanges) -> IndexUpdate {
+        let mut removed_copies: HashMap<&ResourceId, usize> = HashMap::new();
+        for path in pending.removed.iter() {
+            if let Some(meta) = self.path2meta.get(path) {
+                *removed_copies.entry(&meta.id).or_default() += 1;
+            } else {
+                log::warn!("Path {} was not known", path.display());
+            }
+        }
+
+        let deleted: HashSet<ResourceId> = removed_copies
+            .into_iter()
+            .filter(|(id, removed)| *removed >= self.copies(id))
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let added: HashMap<CanonicalPathBuf, ResourceMeta> = pending
+            .scanned
+            .iter()
+            .filter(|(_, meta)| {
+                !self.ids.contains(&meta.id) || deleted.contains(&meta.id)
+            })
+            .map(|(path, meta)| (path.clone(), meta.clone()))
+            .collect();
+
+        let modified_in_place = pending
+            .scanned
+            .iter()
+            .filter_map(|(path, meta)| {
+                let old = &self.path2meta.get(path)?.id;
+                if old == &meta.id {
+                    return None;
+                }
+                Some((path.clone(), (old.clone(), meta.id.clone())))
+            })
+            .collect();
+
+        IndexUpdate {
+            deleted,
+            added,
+            modified_in_place,
+        }
+    }
+
+    /// Registers a hook invoked with every update made by `update`
+    /// and `forget_paths`, e.g. to keep derived data in sync.
+    /// Hooks are invoked in the order they were registered.
+    pub fn on_update<F>(&mut self, hook: F) -> HookHandle
+    where
+        F: FnMut(&IndexUpdate) + Send + Sync + 'static,
+    {
+        let handle = HookHandle(self.next_hook);
+        self.next_hook += 1;
+        self.on_update
+            .push((handle, UpdateHook(Box::new(hook))));
+        handle
+    }
+
+    /// Unregisters a single hook, returns whether it was registered.
+    pub fn remove_hook(&mut self, handle: HookHandle) -> bool {
+        let before = self.on_update.len();
+        self.on_update
+            .retain(|(registered, _)| *registered != handle);
+        self.on_update.len() != before
+    }
+
+    /// Invokes `callback` with every update touching `subdir`, narrowed
+    /// by `IndexUpdate::filter_under`. Changes elsewhere still update
+    /// the index, silently. Other hooks keep being invoked.
+    pub fn watch_subtree<P, F>(
+        &mut self,
+        subdir: P,
+        mut callback: F,
+    ) -> Result<HookHandle, Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&IndexUpdate) + Send + Sync + 'static,
+    {
+        let subdir = CanonicalPathBuf::canonicalize(subdir)?;
+        let mut present: HashSet<ResourceId> = self
+            .path2meta
+            .iter()
+            .filter(|(path, _)| path.starts_with(&subdir))
+            .map(|(_, meta)| meta.id.clone())
+            .collect();
+
+        Ok(self.on_update(move |update| {
+            let inside = update.filter_under(subdir.as_path(), &present);
+            for id in inside.deleted.iter() {
+                present.remove(id);
+            }
+            present.extend(inside.added.values().map(|meta| meta.id.clone()));
+            if !inside.is_empty() {
+                callback(&inside);
+            }
+        }))
+    }
+
+    /// Unregisters every hook.
+    pub fn clear_on_update(&mut self) {
+        self.on_update.clear();
+    }
+
+    /// Every indexed resource reported as added, the way
+    /// a hook would see an index built from scratch.
+    pub fn as_update(&self) -> IndexUpdate {
+        IndexUpdate {
+            deleted: HashSet::new(),
+            added: self.path2meta.clone(),
+            modified_in_place: HashMap::new(),
+        }
+    }
+
+    fn notify(&mut self, update: &IndexUpdate) {
+        let changed = !update.is_empty();
+        if changed {
+            self.generation += 1;
+        }
+        for (_, UpdateHook(hook)) in self.on_update.iter_mut() {
+            hook(update);
+        }
+        // only once the hooks are done, so an interrupted
+        // delivery is repeated by the next `resume`
+        if let Some(path) = self.checkpoint.as_ref().filter(|_| changed) {
+            if let Err(err) = self.store(path) {
+                report!(
+                    self.options,
+                    Level::Error,
+                    "Couldn't store the index to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    /// Count of updates which changed the index since it was built,
+    /// kept across storing and loading.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Stores the index to `path` after every update which changed it,
+    /// once the hooks have seen the update, see `resume`. Failures to
+    /// store are logged, the next checkpoint tries again.
+    pub fn checkpoint_to<P: AsRef<Path>>(&mut self, path: P) {
+        self.checkpoint = Some(path.as_ref().to_owned());
+    }
+
+    /// Recorded events, oldest first.
+    pub fn events(&self) -> impl Iterator<Item = &IndexEvent> {
+        self.events.iter()
+    }
+
+    /// Takes the recorded events out of the index, oldest first.
+    pub fn drain_events(&mut self) -> Vec<IndexEvent> {
+        self.events.drain(..).collect()
+    }
+
+    fn record(&mut self, path: &CanonicalPathBuf, kind: IndexEventKind) {
+        if !self.options.event_log {
+            return;
+        }
+
+        if let Some(capacity) = self.options.event_log_capacity {
+            if capacity == 0 {
+                return;
+            }
+            while self.events.len() >= capacity {
+                self.events.pop_front();
+            }
+        }
+        self.events.push_back(IndexEvent {
+            at: SystemTime::now(),
+            path: path.clone(),
+            kind,
+        });
+    }
+
+    fn copies(&self, id: &ResourceId) -> usize {
+        self.collisions.get(id).copied().unwrap_or(1)
+    }
+
+    /// Fails with `ArkError::RootMissing` if any root is gone,
+    /// or `ArkError::RootUnavailable` if it isn't a directory anymore.
+    fn check_roots(&self) -> Result<(), Error> {
+        for root in self.roots.iter() {
+            let err = match fs::metadata(&root.path) {
+                Ok(metadata) if metadata.is_dir() => continue,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    ArkError::RootMissing(root.path.clone())
+                }
+                _ => ArkError::RootUnavailable(root.path.clone()),
+            };
+            report!(self.options, Level::Error, "{}", err);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Roots which currently exist, canonicalized.
+    pub(crate) fn canonical_roots(&self) -> Vec<PathBuf> {
+        self.roots
+            .iter()
+            .filter_map(|root| fs::canonicalize(&root.path).ok())
+            .collect()
+    }
+
+    /// Indexed resources whose files are gone from the disk.
+    /// Only checks existence of the known paths, nothing is hashed
+    /// and the index is left untouched, see `forget_paths`.
+    /// Every directory is listed once instead of asking for each file,
+    /// which saves round trips on network storage.
+    pub fn missing_resources(&self) -> Result<Vec<Resource>, Error> {
+        self.check_roots()?;
+
+        let mut by_dir: HashMap<&Path, Vec<&CanonicalPathBuf>> = HashMap::new();
+        for path in self.path2meta.keys() {
+            if let Some(dir) = path.as_path().parent() {
+                by_dir.entry(dir).or_default().push(path);
+            }
+        }
+
+        let mut missing = Vec::new();
+        for (dir, paths) in by_dir {
+            let names: HashSet<OsString> = match fs::read_dir(dir) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name())
+                    .collect(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    HashSet::new()
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Couldn't check existence of files in {}: {}",
+                        dir.display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            missing.extend(paths.into_iter().filter(|path| {
+                path.as_path()
+                    .file_name()
+                    .is_some_and(|name| !names.contains(name))
+            }));
+        }
+
+        Ok(missing
+            .into_iter()
+            .map(|path| Resource {
+                path: path.clone(),
+                meta: self.path2meta[path].clone(),
+            })
+            .collect())
+    }
+
+    /// Removes the files which are gone from the disk, like `update`
+    /// without looking for new or edited files, so nothing is hashed,
+    /// see `missing_resources`. Fails with `ArkError::MassDeletionBlocked`
+    /// when `IndexOptions::mass_deletion_guard` is exceeded.
+    pub fn prune_deleted(&mut self) -> Result<IndexUpdate, Error> {
+        let missing: Vec<CanonicalPathBuf> = self
+            .missing_resources()?
+            .into_iter()
+            .map(|resource| resource.path)
+            .collect();
+
+        let (gone, total) = (missing.len(), self.path2meta.len());
+        let limit = self.options.mass_deletion_guard;
+        if limit.is_some_and(|limit| gone as f64 / total as f64 > limit) {
+            report!(
+                self.options,
+                Level::Error,
+                "Pruning would delete {} of {} paths",
+                gone,
+                total
+            );
+            let mut remaining = HashMap::new();
+            for path in missing.iter() {
+                let id = &self.path2meta[path].id;
+                *remaining.entry(id).or_insert_with(|| {
+                    self.collisions.get(id).copied().unwrap_or(1)
+                }) -= 1;
+            }
+            let update = IndexUpdate {
+                deleted: remaining
+                    .into_iter()
+                    .filter(|(_, left)| *left == 0)
+                    .map(|(id, _)| id.clone())
+                    .collect(),
+                ..IndexUpdate::default()
+            };
+            return Err(ArkError::MassDeletionBlocked {
+                gone,
+                total,
+                update,
+            }
+            .into());
+        }
+
+        Ok(self.forget_paths(missing.iter()))
+    }
+
+    /// Removes the given paths from the index without touching the disk.
+    pub fn forget_paths<'a, I>(&mut self, paths: I) -> IndexUpdate
+    where
+        I: IntoIterator<Item = &'a CanonicalPathBuf>,
+    {
+        // forgotten files are found again by the next walk
+        self.dir_times.clear();
+        let deleted = paths
+            .into_iter()
+            .filter_map(|path| {
+                if let Some(meta) = self.path2meta.get(path) {
+                    let kind = IndexEventKind::Deleted(meta.id.clone());
+                    self.record(path, kind);
+                }
+                if self.path2meta.contains_key(path) {
+                    *self.dirty.get_mut() = true;
+                }
+                remove_meta(
+                    path,
+                    &mut self.path2meta,
+                    &mut self.collisions,
+                    &mut self.ids,
+                )
+            })
+            .collect();
+
+        let update = IndexUpdate {
+            deleted,
+            added: HashMap::new(),
+            modified_in_place: HashMap::new(),
+        };
+        self.notify(&update);
+        update
+    }
+
+    /// Captures the state of the index, e.g. to try an `update` and
+    /// roll it back with `restore`. The state is copied as a whole,
+    /// so a checkpoint takes as much memory as the index itself.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            path2meta: self.path2meta.clone(),
+            collisions: self.collisions.clone(),
+            ids: self.ids.clone(),
+            roots: self.roots.clone(),
+            options: self.options.clone(),
+            events: self.events.clone(),
+            streams: self.streams.clone(),
+            suspicious: self.suspicious.clone(),
+            updated: self.updated,
+            scan_errors: self.scan_errors,
+            tags: self.tags.clone(),
+        }
+    }
+
+    /// Brings the index back to the checkpoint. The registered hook
+    /// stays and isn't notified, as nothing on the disk changed.
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        self.path2meta = checkpoint.path2meta;
+        self.collisions = checkpoint.collisions;
+        self.ids = checkpoint.ids;
+        self.roots = checkpoint.roots;
+        self.options = checkpoint.options;
+        self.events = checkpoint.events;
+        self.streams = checkpoint.streams;
+        self.suspicious = checkpoint.suspicious;
+        self.updated = checkpoint.updated;
+        self.scan_errors = checkpoint.scan_errors;
+        self.tags = checkpoint.tags;
+        self.dir_times.clear();
+        *self.dirty.get_mut() = true;
+    }
+
+    /// Whether the index changed since it was last stored or loaded,
+    /// e.g. by an update or tagging. Indexes which were never stored
+    /// are dirty.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_clean(&self) {
+        self.dirty.store(false, Ordering::Relaxed);
+    }
+
+    /// Makes the next `update` re-hash the file even if its
+    /// modification time didn't change, e.g. after a copy tool
+    /// known to preserve mtimes wrote to it.
+    pub fn mark_suspicious<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<(), Error> {
+        let path = self.key_of(path.as_ref()).ok_or_else(|| {
+            Error::msg(format!("{} can't be resolved", path.as_ref().display()))
+        })?;
+        if !self.path2meta.contains_key(&path) {
+            return Err(Error::msg(format!(
+                "{} is not indexed",
+                path.display()
+            )));
+        }
+        self.suspicious.insert(path);
+        Ok(())
+    }
+
+    /// Hashes content which isn't a file, e.g. piped to stdin, and
+    /// remembers its id under `name`, replacing the previous stream
+    /// of that name. Streams aren't paths: `update` doesn't touch them,
+    /// they don't take part in collisions and aren't stored.
+    pub fn add_reader<R: Read>(
+        &mut self,
+        name: &str,
+        reader: R,
+    ) -> Result<ResourceId, Error> {
+        let id = ResourceId::from_reader(reader)?;
+        self.streams.insert(name.to_owned(), id.clone());
+        Ok(id)
+    }
+
+    /// Id of the stream added under `name` by `add_reader`.
+    pub fn stream(&self, name: &str) -> Option<&ResourceId> {
+        self.streams.get(name)
+    }
+
+    /// Attaches the tag to the content, so every copy of it is tagged.
+    /// Tags are stored with the index and journaled by `JournalStore`.
+    /// Returns whether the content wasn't tagged so already.
+    pub fn tag(&mut self, id: &ResourceId, tag: &str) -> bool {
+        let added = self
+            .tags
+            .entry(id.clone())
+            .or_default()
+            .insert(tag.to_owned());
+        if added {
+            *self.dirty.get_mut() = true;
+        }
+        added
+    }
+
+    /// Every tagged content, for stores persisting tags.
+    pub(crate) fn tag_map(&self) -> &HashMap<ResourceId, HashSet<String>> {
+        &self.tags
+    }
+
+    /// Replaces every tag, e.g. by tags loaded from a store.
+    pub(crate) fn replace_tags(
+        &mut self,
+        tags: HashMap<ResourceId, HashSet<String>>,
+    ) {
+        self.tags = tags;
+    }
+
+    /// Tags of the content, sorted.
+    pub fn tags(&self, id: &ResourceId) -> Vec<&str> {
+        let mut tags: Vec<&str> = match self.tags.get(id) {
+            Some(tags) => tags.iter().map(String::as_str).collect(),
+            None => vec![],
+        };
+        tags.sort();
+        tags
+    }
+
+    /// Tags the content of every resource matching the predicate,
+    /// e.g. all `.raw` files. Returns the number of distinct contents
+    /// tagged, including the ones which had the tag already.
+    pub fn tag_matching<F>(&mut self, predicate: F, tag: &str) -> usize
+    where
+        F: Fn(&CanonicalPathBuf, &ResourceMeta) -> bool,
+    {
+        let matching: HashSet<ResourceId> = self
+            .path2meta
+            .iter()
+            .filter(|(path, meta)| predicate(path, meta))
+            .map(|(_, meta)| meta.id.clone())
+            .collect();
+        for id in matching.iter() {
+            self.tag(id, tag);
+        }
+        matching.len()
+    }
+
+    /// Re-hashes a single indexed file rewritten in place, e.g.
+    /// transcoded, and moves it from the old id to the new one.
+    /// Hooks and the event log see it as any other change.
+    pub fn replace_content<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<ContentChange, Error> {
+        let path = self.key_of(path.as_ref()).ok_or_else(|| {
+            Error::msg(format!("{} can't be resolved", path.as_ref().display()))
+        })?;
+        let entry = entry_at(&path)?;
+        let old = match self.path2meta.get(&path) {
+            Some(meta) => meta.id.clone(),
+            None => {
+                return Err(Error::msg(format!(
+                    "{} is not indexed",
+                    path.display()
+                )))
+            }
+        };
+        let (path, meta) =
+            ResourceMeta::scan_with_options(path, entry, &self.options)?;
+        if self.options.blocked_ids.contains(&meta.id) {
+            return Err(Error::msg(format!(
+                "{} has blocked content now",
+                path.display()
+            )));
+        }
+
+        let change = ContentChange {
+            old,
+            new: meta.id.clone(),
+        };
+        *self.dirty.get_mut() = true;
+        if !change.changed() {
+            self.path2meta.insert(path, meta);
+            return Ok(change);
+        }
+
+        let deleted: HashSet<ResourceId> = remove_meta(
+            &path,
+            &mut self.path2meta,
+            &mut self.collisions,
+            &mut self.ids,
+        )
+        .into_iter()
+        .collect();
+        let mut added = HashMap::new();
+        if !self.ids.contains(&change.new) {
+            added.insert(path.clone(), meta.clone());
+        }
+        add_meta(
+            path.clone(),
+            meta,
+            &mut self.path2meta,
+            &mut self.collisions,
+            &mut self.ids,
+        );
+
+        self.carry_tags(&change.old, &change.new);
+        if let Some(classifier) = self.options.classifier.clone() {
+            self.classify(&classifier, &path);
+        }
+        let kind = IndexEventKind::Changed {
+            old: change.old.clone(),
+            new: change.new.clone(),
+        };
+        self.record(&path, kind);
+        let modified_in_place =
+            HashMap::from([(path, (change.old.clone(), change.new.clone()))]);
+        self.notify(&IndexUpdate {
+            deleted,
+            added,
+            modified_in_place,
+        });
+        Ok(change)
+    }
+
+    /// Removes every copy of the content from the index, and from the
+    /// disk if `delete_files` is set. Returns the affected paths,
+    /// sorted. When a file can't be deleted, the files deleted before
+    /// it are still forgotten and the error is returned.
+    pub fn purge_id(
+        &mut self,
+        id: &ResourceId,
+        delete_files: bool,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let mut paths: Vec<CanonicalPathBuf> = self
+            .path2meta
+            .iter()
+            .filter(|(_, meta)| &meta.id == id)
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.sort();
+
+        let mut purged = Vec::with_capacity(paths.len());
+        let mut failure = None;
+        for path in paths {
+            if delete_files {
+                log::info!("Deleting {}", path.display());
+                if let Err(err) = fs::remove_file(&path) {
+                    failure = Some(Error::msg(format!(
+                        "Couldn't delete {}: {}",
+                        path.display(),
+                        err
+                    )));
+                    break;
+                }
+            }
+            purged.push(path);
+        }
+
+        self.forget_paths(purged.iter());
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(purged
+                .into_iter()
+                .map(|path| path.into_path_buf())
+                .collect()),
+        }
+    }
+
+    /// Moves copies of duplicated content into `dest`, e.g. to review
+    /// them before deleting, all but the first one of each group if
+    /// `keep_one` is set. Names taken in `dest` get a ` (n)` suffix.
+    /// Returns the moves made, which are applied to the index as well.
+    /// `dest` should be under a root, otherwise the next update
+    /// forgets the moved files.
+    pub fn gather_duplicates_into<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        keep_one: bool,
+    ) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+        fs::create_dir_all(&dest)?;
+        let dest = CanonicalPathBuf::canonicalize(dest)?;
+
+        let mut groups: Vec<(ResourceId, Vec<CanonicalPathBuf>)> = self
+            .duplicates()
+            .into_iter()
+            .map(|(id, paths)| (id, paths.into_iter().cloned().collect()))
+            .collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut moves = Vec::new();
+        for (id, mut paths) in groups {
+            paths.sort();
+            let skipped = if keep_one { 1 } else { 0 };
+            for from in paths.into_iter().skip(skipped) {
+                if from.as_path().parent() == Some(dest.as_path()) {
+                    continue;
+                }
+                let name = match from.as_path().file_name() {
+                    Some(name) => name.to_owned(),
+                    None => continue,
+                };
+                let to = free_path(dest.as_path(), &name);
+                log::info!("Moving {} to {}", from.display(), to.display());
+                fs::rename(&from, &to).map_err(|err| {
+                    Error::msg(format!(
+                        "Couldn't move {}: {}",
+                        from.display(),
+                        err
+                    ))
+                })?;
+
+                let to = CanonicalPathBuf::canonicalize(&to)?;
+                if let Some(mut meta) = self.path2meta.remove(&from) {
+                    meta.name = to.as_path().file_name().map(Into::into);
+                    self.path2meta.insert(to.clone(), meta);
+                }
+                *self.dirty.get_mut() = true;
+                self.record(&from, IndexEventKind::Deleted(id.clone()));
+                self.record(&to, IndexEventKind::Added(id.clone()));
+                moves.push((from.into_path_buf(), to.into_path_buf()));
+            }
+        }
+        Ok(moves)
+    }
+
+    /// Asks `resolve` what to do with each group of duplicates, in
+    /// order of ids, and removes the copies it gives up from the index,
+    /// and from the disk if `delete_files` is set. Returns the removed
+    /// paths. Resolutions naming paths outside of their group are
+    /// rejected before anything is removed.
+    pub fn resolve_collisions<F>(
+        &mut self,
+        delete_files: bool,
+        mut resolve: F,
+    ) -> Result<Vec<PathBuf>, Error>
+    where
+        F: FnMut(&ResourceId, &[&CanonicalPathBuf]) -> CollisionResolution,
+    {
+        let mut groups: Vec<(ResourceId, Vec<&CanonicalPathBuf>)> =
+            self.duplicates().into_iter().collect();
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut doomed: Vec<CanonicalPathBuf> = Vec::new();
+        for (id, mut paths) in groups {
+            paths.sort();
+            let removed = match resolve(&id, &paths) {
+                CollisionResolution::KeepAll => continue,
+                CollisionResolution::KeepOnly(kept) => {
+                    if !paths.contains(&&kept) {
+                        return Err(Error::msg(format!(
+                            "{} is not a copy of {}",
+                            kept.display(),
+                            id
+                        )));
+                    }
+                    paths.retain(|path| **path != kept);
+                    paths
+                }
+                CollisionResolution::Delete(removed) => {
+                    if let Some(path) =
+                        removed.iter().find(|path| !paths.contains(path))
+                    {
+                        return Err(Error::msg(format!(
+                            "{} is not a copy of {}",
+                            path.display(),
+                            id
+                        )));
+                    }
+                    paths.retain(|path| removed.contains(path));
+                    paths
+                }
+            };
+            doomed.extend(removed.into_iter().cloned());
+        }
+
+        let mut removed = Vec::with_capacity(doomed.len());
+        let mut failure = None;
+        for path in doomed {
+            if delete_files {
+                log::info!("Deleting {}", path.display());
+                if let Err(err) = fs::remove_file(&path) {
+                    failure = Some(Error::msg(format!(
+                        "Couldn't delete {}: {}",
+                        path.display(),
+                        err
+                    )));
+                    break;
+                }
+            }
+            removed.push(path);
+        }
+
+        self.forget_paths(removed.iter());
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(removed
+                .into_iter()
+                .map(|path| path.into_path_buf())
+                .collect()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexRepr {
+    root: EncodedOsStr,
+    /// All the roots, if there is more than one or
+    /// the first one filters extensions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roots: Vec<RootRepr>,
+    #[serde(default)]
+    options: IndexOptions,
+    resources: Vec<(EncodedOsStr, ResourceMeta)>,
+    /// Tags of contents, see `ResourceIndex::tag`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<(ResourceId, Vec<String>)>,
+    /// See `ResourceIndex::generation`.
+    #[serde(default)]
+    generation: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RootRepr {
+    path: EncodedOsStr,
+    extensions: Option<Vec<String>>,
+}
+
+impl Serialize for ResourceIndex {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        // sorted, so that unchanged indexes serialize identically
+        let mut resources: Vec<(&CanonicalPathBuf, &ResourceMeta)> =
+            self.path2meta.iter().collect();
+        resources.sort_by_key(|(path, _)| *path);
+
+        let roots = if self.roots.len() == 1
+            && self.roots[0].extensions.is_none()
+        {
+            vec![]
+        } else {
+            self.roots
+                .iter()
+                .map(|root| RootRepr {
+                    path: root.path.as_os_str().into(),
+                    extensions: root.extensions.as_ref().map(|extensions| {
+                        let mut extensions: Vec<String> =
+                            extensions.iter().cloned().collect();
+                        extensions.sort();
+                        extensions
+                    }),
+                })
+                .collect()
+        };
+
+        let mut tags: Vec<(ResourceId, Vec<String>)> = self
+            .tags
+            .iter()
+            .filter(|(_, tags)| !tags.is_empty())
+            .map(|(id, tags)| {
+                let mut tags: Vec<String> = tags.iter().cloned().collect();
+                tags.sort();
+                (id.clone(), tags)
+            })
+            .collect();
+        tags.sort();
+
+        IndexRepr {
+            root: self.root().as_os_str().into(),
+            roots,
+            options: self.options.clone(),
+            resources: resources
+                .into_iter()
+                .map(|(path, meta)| (path.as_os_str().into(), meta.clone()))
+                .collect(),
+            tags,
+            generation: self.generation,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ResourceIndex {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+
+        let repr = IndexRepr::deserialize(deserializer)?;
+        let mut roots = Vec::with_capacity(repr.roots.len().max(1));
+        for root in repr.roots {
+            let path =
+                OsString::try_from(root.path).map_err(D::Error::custom)?;
+            roots.push(IndexRoot {
+                path: path.into(),
+                extensions: root
+                    .extensions
+                    .map(|extensions| extensions.into_iter().collect()),
+            });
+        }
+        if roots.is_empty() {
+            let path =
+                OsString::try_from(repr.root).map_err(D::Error::custom)?;
+            roots.push(IndexRoot::new(path));
+        }
+
+        let mut metadata = Vec::with_capacity(repr.resources.len());
+        for (path, meta) in repr.resources {
+            let path = OsString::try_from(path).map_err(D::Error::custom)?;
+            // paths were canonical when stored, the files
+            // themselves may be gone until the next update
+            let path = unsafe { CanonicalPath::from_path_unchecked(&path) }
+                .to_canonical_path_buf();
+            metadata.push((path, meta));
+        }
+
+        let mut index = ResourceIndex::assemble(roots, repr.options, metadata);
+        index.tags = repr
+            .tags
+            .into_iter()
+            .map(|(id, tags)| (id, tags.into_iter().collect()))
+            .collect();
+        index.generation = repr.generation;
+        Ok(index)
+    }
+}
+
+pub(crate) fn discover_paths<P: AsRef<Path>>(
+    root_path: P,
+    options: &IndexOptions,
+    extensions: Option<&HashSet<String>>,
+) -> Result<HashMap<CanonicalPathBuf, DirEntry>, Error> {
+    let mut dirs = DirTimes::new();
+    let excluded = Exclusions::default();
+    discover_with_dirs(root_path, options, extensions, &mut dirs, &excluded)
+}
+
+/// Whether the path is a Linux directory whose files describe
+/// the running system rather than store anything.
+#[cfg(target_os = "linux")]
+fn is_pseudo_filesystem(path: &Path) -> bool {
+    ["/proc", "/sys", "/dev"]
+        .iter()
+        .any(|pseudo| path == Path::new(pseudo))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_pseudo_filesystem(_path: &Path) -> bool {
+    false
+}
+
+/// Refuses roots of filesystems and system directories,
+/// unless `allow_system_roots` is set.
+fn check_system_root(root: &Path, options: &IndexOptions) -> Result<(), Error> {
+    let resolved = fs::canonicalize(root).unwrap_or_else(|_| root.to_owned());
+    let system = resolved.parent().is_none() || is_pseudo_filesystem(&resolved);
+    if !system {
+        return Ok(());
+    }
+    if !options.allow_system_roots {
+        return Err(ArkError::SystemRoot(root.to_owned()).into());
+    }
+    report!(
+        options,
+        Level::Warn,
+        "Indexing system directory {}",
+        root.display()
+    );
+    Ok(())
+}
+
+/// Same as `discover_paths`, also collecting the modification times
+/// of the walked directories, unknown times being the epoch, and
+/// the paths left out.
+fn discover_with_dirs<P: AsRef<Path>>(
+    root_path: P,
+    options: &IndexOptions,
+    extensions: Option<&HashSet<String>>,
+    dirs: &mut DirTimes,
+    excluded: &Exclusions,
+) -> Result<HashMap<CanonicalPathBuf, DirEntry>, Error> {
+    check_system_root(root_path.as_ref(), options)?;
+    report!(
+        options,
+        Level::Info,
+        "Discovering all files under path {}",
+        root_path.as_ref().display()
+    );
+
+    // directories entered so far, WalkDir itself only notices links
+    // pointing back to an ancestor and knows nothing about mounts
+    let mut visited: HashSet<DirKey> = HashSet::new();
+    let mut revisited: Option<PathBuf> = None;
+    let mut looped: Option<PathBuf> = None;
+
+    let follow = options.symlinks == SymlinkMode::FollowTarget;
+    let entries = WalkDir::new(&root_path)
+        .follow_links(follow)
+        .into_iter()
+        .filter_entry(|entry| {
+            let hidden = match &options.hidden_fn {
+                Some(filter) => (filter.0)(entry),
+                None => is_hidden(entry, &options.hidden_exceptions),
+            };
+            if hidden {
+                excluded.filter(entry.path(), Exclusion::Hidden);
+                return false;
+            }
+            if entry.depth() > 0 && is_pseudo_filesystem(entry.path()) {
+                log::info!("Skipping {}", entry.path().display());
+                excluded.filter(entry.path(), Exclusion::SystemDirectory);
+                return false;
+            }
+            if !entry.file_type().is_dir() {
+                return true;
+            }
+            match dir_key(entry) {
+                Some(key) => {
+                    if visited.insert(key) {
+                        return true;
+                    }
+                    report!(
+                        options,
+                        Level::Warn,
+                        "Directory {} was visited already",
+                        entry.path().display()
+                    );
+                    revisited.get_or_insert_with(|| entry.path().to_owned());
+                    excluded.filter(entry.path(), Exclusion::Revisited);
+                    false
+                }
+                None => true,
+            }
+        })
+        .filter_map(|result| match result {
+            Ok(entry) => {
+                let path = entry.path();
+                if entry.file_type().is_symlink() {
+                    return match options.symlinks {
+                        SymlinkMode::HashLinkTarget
+                            if has_extension(path, extensions) =>
+                        {
+                            link_path(path).map(|link| (link, entry))
+                        }
+                        SymlinkMode::HashLinkTarget => {
+                            excluded.filter(path, Exclusion::Extension);
+                            None
+                        }
+                        _ => {
+                            excluded.filter(path, Exclusion::Symlink);
+                            None
+                        }
+                    };
+                }
+                if entry.file_type().is_dir() {
+                    let modified = entry
+                        .metadata()
+                        .ok()
+                        .and_then(|metadata| metadata.modified().ok());
+                    dirs.insert(
+                        path.to_owned(),
+                        modified.unwrap_or(SystemTime::UNIX_EPOCH),
+                    );
+                }
+                if !entry.file_type().is_dir()
+                    && has_extension(path, extensions)
+                {
+                    if has_trailing_dot(path) {
+                        return trailing_dot_entry(entry, options, excluded);
+                    }
+                    let canonicalized = options
+                        .retry
+                        .retry(|| CanonicalPathBuf::canonicalize(path));
+                    match canonicalized {
+                        Ok(canonical_path) => Some((canonical_path, entry)),
+                        Err(msg) => {
+                            report!(
+                                options,
+                                Level::Error,
+                                "Couldn't canonicalize {}:\n{}",
+                                path.display(),
+                                msg
+                            );
+                            excluded.unreadable(path, msg.to_string());
+                            None
+                        }
+                    }
+                } else {
+                    if !entry.file_type().is_dir() {
+                        excluded.filter(path, Exclusion::Extension);
+                    }
+                    None
+                }
+            }
+            Err(msg) => {
+                report!(options, Level::Error, "Error during walking: {}", msg);
+                let path = msg.path().unwrap_or(root_path.as_ref());
+                excluded.unreadable(path, msg.to_string());
+                if msg.loop_ancestor().is_some() {
+                    looped = msg.path().map(Path::to_owned);
+                }
+                None
+            }
+        })
+        .collect();
+
+    match looped.or(revisited) {
+        Some(path) if options.symlink_loops == SymlinkLoops::Fail => Err(
+            Error::msg(format!("Symlink loop detected at {}", path.display())),
+        ),
+        _ => Ok(entries),
+    }
+}
+
+/// The path relative to the innermost of canonical `roots` containing
+/// it, separated by `/` on every platform.
+pub(crate) fn portable_relative_path(roots: &[PathBuf], path: &Path) -> String {
+    let relative = roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(root).ok())
+        .min_by_key(|relative| relative.components().count())
+        .unwrap_or(path);
+    let parts: Vec<_> = relative
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+fn glob_matches(glob: &[char], path: &[char]) -> bool {
+    match glob.split_first() {
+        None => path.is_empty(),
+        Some(('*', rest)) => match rest.split_first() {
+            Some(('*', rest)) => {
+                // `**/` matches no directory at all as well
+                (rest.first() == Some(&'/') && glob_matches(&rest[1..], path))
+                    || (0..=path.len()).any(|i| glob_matches(rest, &path[i..]))
+            }
+            _ => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != '/')
+                .any(|i| glob_matches(rest, &path[i..])),
+        },
+        Some(('?', rest)) => match path.split_first() {
+            Some((c, path)) => *c != '/' && glob_matches(rest, path),
+            None => false,
+        },
+        Some((expected, rest)) => match path.split_first() {
+            Some((c, path)) => c == expected && glob_matches(rest, path),
+            None => false,
+        },
+    }
+}
+
+/// Whether the name ends with a dot or a space,
+/// which Win32 paths don't keep.
+#[cfg(windows)]
+fn has_trailing_dot(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy())
+        .is_some_and(|name| name.ends_with('.') || name.ends_with(' '))
+}
+
+#[cfg(not(windows))]
+fn has_trailing_dot(_path: &Path) -> bool {
+    false
+}
+
+/// Keeps a file with a trailing dot or space by its extended-length
+/// path, or leaves it out, depending on `trailing_dot_names`.
+fn trailing_dot_entry(
+    entry: DirEntry,
+    options: &IndexOptions,
+    excluded: &Exclusions,
+) -> Option<(CanonicalPathBuf, DirEntry)> {
+    let path = entry.path();
+    match options.trailing_dot_names {
+        // the canonicalized directory is extended-length already
+        TrailingDotNames::Preserve => {
+            let preserved = link_path(path)?;
+            Some((preserved, entry))
+        }
+        TrailingDotNames::Exclude => {
+            report!(
+                options,
+                Level::Warn,
+                "Skipping {}, its name ends with a dot or a space",
+                path.display()
+            );
+            excluded.filter(path, Exclusion::TrailingDotName);
+            None
+        }
+    }
+}
+
+/// Path of the link itself: its canonicalized directory
+/// joined with its name, the link isn't resolved.
+fn link_path(path: &Path) -> Option<CanonicalPathBuf> {
+    let name = path.file_name()?;
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty());
+    match fs::canonicalize(dir.unwrap_or_else(|| Path::new("."))) {
+        Ok(dir) => {
+            let link = dir.join(name);
+            Some(
+                unsafe { CanonicalPath::from_path_unchecked(&link) }
+                    .to_canonical_path_buf(),
+            )
+        }
+        Err(msg) => {
+            log::error!("Couldn't canonicalize {}:\n{}", path.display(), msg);
+            None
+        }
+    }
+}
+
+/// Creation time of the entry, or modification time if unknown.
+fn date_of(meta: &ResourceMeta) -> SystemTime {
+    meta.created.unwrap_or(meta.modified)
+}
+
+/// Id of the first `prefix_len` bytes of the file,
+/// which must be at least that long.
+fn prefix_id(path: &Path, prefix_len: u64) -> io::Result<ResourceId> {
+    let source = fs::File::open(path)?;
+    ResourceId::partial_from_reader(prefix_len, source, prefix_len)
+}
+
+/// Whether scanning failed because the file vanished, or became empty
+/// while empty files are skipped, so it is removed rather than failed.
+fn is_gone(err: &Error) -> bool {
+    let not_found = |err: &io::Error| err.kind() == io::ErrorKind::NotFound;
+    matches!(err.downcast_ref(), Some(ArkError::EmptyResource(_)))
+        || err
+            .downcast_ref::<io::Error>()
+            .is_some_and(not_found)
+        || err
+            .downcast_ref::<walkdir::Error>()
+            .and_then(walkdir::Error::io_error)
+            .is_some_and(not_found)
+}
+
+/// Walker entry of a single path, as if it was discovered.
+fn path_entry(path: &Path) -> Result<(CanonicalPathBuf, DirEntry), Error> {
+    let canonical_path = CanonicalPathBuf::canonicalize(path)?;
+    let entry = entry_at(&canonical_path)?;
+    Ok((canonical_path, entry))
+}
+
+/// Walker entry of an indexed path, links aren't followed.
+fn entry_at(path: &CanonicalPathBuf) -> Result<DirEntry, Error> {
+    let entry = WalkDir::new(path.as_path())
+        .max_depth(0)
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::msg("Path vanished"))??;
+    Ok(entry)
+}
+
+fn scan_metadata(
+    entries: HashMap<CanonicalPathBuf, DirEntry>,
+    options: &IndexOptions,
+    cache: Option<&ChecksumCache>,
+    excluded: &Exclusions,
+) -> HashMap<CanonicalPathBuf, ResourceMeta> {
+    report!(options, Level::Info, "Scanning metadata");
+
+    if !options.sequential_scan {
+        let threads = options
+            .parallelism
+            .threads()
+            .min(options.max_open_files.unwrap_or(usize::MAX))
+            .min(entries.len());
+        if threads <= 1 {
+            return entries
+                .into_iter()
+                .filter_map(|(path, entry)| {
+                    scan_entry(path, entry, options, cache, excluded)
+                })
+                .collect();
+        }
+
+        // workers take files one by one, so a big file
+        // doesn't hold up the others' share
+        let queue = Mutex::new(entries.into_iter());
+        let next = || queue.lock().unwrap().next();
+        return std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut scanned = vec![];
+                        while let Some((path, entry)) = next() {
+                            scanned.extend(scan_entry(
+                                path, entry, options, cache, excluded,
+                            ));
+                        }
+                        scanned
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect()
+        });
+    }
+
+    let mut entries: Vec<(CanonicalPathBuf, DirEntry)> =
+        entries.into_iter().collect();
+    entries.sort_by_cached_key(|(path, entry)| {
+        (disk_position(entry), path.clone())
+    });
+
+    let mut metadata = HashMap::with_capacity(entries.len());
+    let mut entries = entries.into_iter().peekable();
+    while let Some((path, entry)) = entries.next() {
+        if let Some((next, _)) = entries.peek() {
+            readahead(next.as_path());
+        }
+        if let Some((path, meta)) =
+            scan_entry(path, entry, options, cache, excluded)
+        {
+            metadata.insert(path, meta);
+        }
+    }
+    metadata
+}
+
+fn scan_entry(
+    path: CanonicalPathBuf,
+    entry: DirEntry,
+    options: &IndexOptions,
+    cache: Option<&ChecksumCache>,
+    excluded: &Exclusions,
+) -> Option<(CanonicalPathBuf, ResourceMeta)> {
+    log::trace!("\n\t{:?}\n\t\t{:?}", path, entry);
+
+    let result = ResourceMeta::scan_reusing(
+        path.clone(),
+        entry,
+        options,
+        |node, size, modified| cache?.get(node, size, modified),
+    );
+    match result {
+        Err(msg)
+            if matches!(
+                msg.downcast_ref(),
+                Some(ArkError::EmptyResource(_))
+            ) =>
+        {
+            log::debug!("Skipping {}", msg);
+            excluded.filter(path.as_path(), Exclusion::Empty);
+            None
+        }
+        Err(msg) => {
+            report!(
+                options,
+                Level::Error,
+                "Couldn't retrieve metadata for {}:\n{}",
+                path.display(),
+                msg
+            );
+            excluded.unscannable(path.as_path(), msg.to_string());
+            None
+        }
+        Ok((path, meta)) if options.blocked_ids.contains(&meta.id) => {
+            log::debug!("Skipping blocked {}", path.display());
+            excluded.filter(path.as_path(), Exclusion::Blocked);
+            None
+        }
+        Ok(meta) => Some(meta),
+    }
+}
+
+/// Removed paths which are now ancestors of created ones, i.e. files
+/// replaced by directories, and created paths which were ancestors
+/// of removed ones, i.e. directories replaced by files.
+fn retyped_paths<'a, I>(
+    removed: &[CanonicalPathBuf],
+    created: I,
+) -> HashSet<PathBuf>
+where
+    I: Iterator<Item = &'a CanonicalPathBuf>,
+{
+    let created: Vec<&Path> = created.map(|path| path.as_path()).collect();
+    let parents_of_removed: HashSet<&Path> = removed
+        .iter()
+        .flat_map(|path| path.as_path().ancestors().skip(1))
+        .collect();
+    let parents_of_created: HashSet<&Path> = created
+        .iter()
+        .flat_map(|path| path.ancestors().skip(1))
+        .collect();
+
+    removed
+        .iter()
+        .map(|path| path.as_path())
+        .filter(|path| parents_of_created.contains(path))
+        .chain(
+            created
+                .iter()
+                .copied()
+                .filter(|path| parents_of_removed.contains(path)),
+        )
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// Identity of a directory: device and inode on Unix, so the same
+/// directory is recognized behind bind mounts, and the canonical
+/// path elsewhere.
+#[cfg(unix)]
+type DirKey = (u64, u64);
+#[cfg(not(unix))]
+type DirKey = PathBuf;
+
+#[cfg(unix)]
+fn dir_key(entry: &DirEntry) -> Option<DirKey> {
+    use std::os::unix::fs::MetadataExt;
+
+    let metadata = entry.metadata().ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_key(entry: &DirEntry) -> Option<DirKey> {
+    fs::canonicalize(entry.path()).ok()
+}
+
+#[cfg(unix)]
+fn disk_position(entry: &DirEntry) -> u64 {
+    use walkdir::DirEntryExt;
+
+    entry.ino()
+}
+
+#[cfg(not(unix))]
+fn disk_position(_entry: &DirEntry) -> u64 {
+    0
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn readahead(path: &Path) {
+    use std::os::unix::io::AsRawFd;
+
+    // the hint outlives the descriptor, pages are
+    // read into the cache asynchronously
+    if let Ok(file) = std::fs::File::open(path) {
+        let result = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                0,
+                0,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if result != 0 {
+            log::debug!(
+                "Couldn't advise readahead for {}: {}",
+                path.display(),
+                result
+            );
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn readahead(_path: &Path) {}
+
+fn add_meta(
+    path: CanonicalPathBuf,
+    meta: ResourceMeta,
+    path2meta: &mut HashMap<CanonicalPathBuf, ResourceMeta>,
+    collisions: &mut HashMap<ResourceId, usize>,
+    ids: &mut HashSet<ResourceId>,
+) {
+    let id = meta.id.clone();
+    path2meta.insert(path, meta);
+
+    if ids.contains(&id) {
+        if let Some(nonempty) = collisions.get_mut(&id) {
+            *nonempty += 1;
+        } else {
+            collisions.insert(id, 2);
+        }
+    } else {
+        ids.insert(id.clone());
+    }
+}
+
+fn content_category(meta: &ResourceMeta) -> String {
+    let extension = match &meta.extension {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => return "unknown".to_owned(),
+    };
+    let category = match extension.as_str() {
+        "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" | "svg" | "tif"
+        | "tiff" | "heic" | "raw" => "image",
+        "mp4" | "mkv" | "avi" | "mov" | "webm" | "wmv" | "m4v" => "video",
+        "mp3" | "flac" | "ogg" | "wav" | "m4a" | "aac" | "opus" => "audio",
+        "txt" | "md" | "csv" | "json" | "xml" | "html" | "htm" => "text",
+        "pdf" | "doc" | "docx" | "odt" | "xls" | "xlsx" | "ods" | "ppt"
+        | "pptx" | "odp" | "rtf" | "epub" => "document",
+        "zip" | "tar" | "gz" | "bz2" | "xz" | "7z" | "rar" | "zst" => "archive",
+        _ => return extension,
+    };
+    category.to_owned()
+}
+
+pub(crate) fn common_ancestor<'a, I: Iterator<Item = &'a Path>>(
+    mut dirs: I,
+) -> PathBuf {
+    let mut ancestor = match dirs.next() {
+        Some(first) => first.to_owned(),
+        None => return PathBuf::new(),
+    };
+    for dir in dirs {
+        while !dir.starts_with(&ancestor) {
+            if !ancestor.pop() {
+                break;
+            }
+        }
+    }
+    ancestor
+}
+
+/// Returns the id if the last path with this id was removed.
+fn remove_meta(
+    path: &CanonicalPathBuf,
+    path2meta: &mut HashMap<CanonicalPathBuf, ResourceMeta>,
+    collisions: &mut HashMap<ResourceId, usize>,
+    ids: &mut HashSet<ResourceId>,
+) -> Option<ResourceId> {
+    if let Some(meta) = path2meta.remove(path) {
+        let k = collisions.remove(&meta.id).unwrap_or(1);
+        if k > 2 {
+            collisions.insert(meta.id, k - 1);
+            None
+        } else if k == 2 {
+            // the single remaining copy is not a collision anymore
+            None
+        } else {
+            log::debug!("Removing {:?} from index", meta.id);
+            ids.remove(&meta.id);
+            Some(meta.id)
+        }
+    } else {
+        log::warn!("Path {} was not known", path.display());
+        None
+    }
+}
+
+fn has_extension(path: &Path, extensions: Option<&HashSet<String>>) -> bool {
+    match extensions {
+        None => true,
+        Some(extensions) => path
+            .extension()
+            .map(|extension| {
+                extensions.contains(&extension.to_string_lossy().to_lowercase())
+            })
+            .unwrap_or(false),
+    }
+}
+
+fn is_hidden(entry: &DirEntry, exceptions: &HashSet<String>) -> bool {
+    // lossy conversion keeps the leading dot
+    // of names which are not valid UTF-8
+    let name = entry.file_name().to_string_lossy();
+    name.starts_with('.') && !exceptions.contains(name.as_ref())
 }
 
 type Paths = HashSet<CanonicalPathBuf>;
+
+type DirTimes = HashMap<PathBuf, SystemTime>;
+
+/// A directory with its indexed entries, see `group_by_directory`.
+pub type DirectoryGroup<'a> =
+    (&'a Path, Vec<(&'a CanonicalPathBuf, &'a ResourceMeta)>);
+
+/// `dir/name` if it is free, `dir/stem (n).ext` with the
+/// lowest free `n` otherwise.
+fn free_path(dir: &Path, name: &OsStr) -> PathBuf {
+    let path = dir.join(name);
+    if fs::symlink_metadata(&path).is_err() {
+        return path;
+    }
+    let name = Path::new(name);
+    let stem = name
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let extension = name
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()));
+    (1..)
+        .map(|n| {
+            let extension = extension.as_deref().unwrap_or("");
+            dir.join(format!("{} ({}){}", stem, n, extension))
+        })
+        .find(|path| fs::symlink_metadata(path).is_err())
+        .expect("some suffix is free")
+}
+
+/// Next number of the SplitMix64 sequence, good enough for sampling.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// How long before a walk a directory must have been modified for its
+/// time to be trusted, covering coarse timestamps, e.g. 2s on FAT.
+const DIR_TIME_SLACK: Duration = Duration::from_secs(2);
+
+/// The directory times if all of them can be trusted, none otherwise:
+/// a file created in the same tick as the walk may not have moved the
+/// time of its directory visibly.
+fn trusted_dirs(dirs: DirTimes, walked: SystemTime) -> DirTimes {
+    let trusted = dirs
+        .values()
+        .all(|time| *time + DIR_TIME_SLACK < walked);
+    if trusted {
+        dirs
+    } else {
+        DirTimes::new()
+    }
+}
+
+/// How many first bytes of files are compared to find partial copies.
+const PARTIAL_COPY_PROBE: u64 = 4096;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn fixture() -> TempDir {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("b.txt"), b"second").unwrap();
+        fs::write(root.path().join("nested/c.txt"), b"first").unwrap();
+        fs::copy("./tests/lena.jpg", root.path().join("nested/lena.jpg"))
+            .unwrap();
+        root
+    }
+
+    #[test]
+    fn sequential_scan_builds_identical_index() {
+        let root = fixture();
+
+        let default = ResourceIndex::build(root.path()).unwrap();
+        let sequential = ResourceIndex::build_with_options(
+            root.path(),
+            IndexOptions {
+                sequential_scan: true,
+                ..IndexOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(default.size(), 4);
+        assert_eq!(default.path2meta, sequential.path2meta);
+        assert_eq!(default.collisions, sequential.collisions);
+        assert_eq!(default.ids, sequential.ids);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn meta_describes_file_without_restat() {
+        let root = fixture();
+        // followed links are indexed at their target,
+        // which lies outside of the root to stay apart
+        let outside = tempfile::tempdir().unwrap();
+        let target = outside.path().join("target.jpg");
+        fs::write(&target, b"target").unwrap();
+        std::os::unix::fs::symlink(&target, root.path().join("link.jpg"))
+            .unwrap();
+        let options = IndexOptions {
+            symlinks: SymlinkMode::FollowTarget,
+            ..IndexOptions::default()
+        };
+
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        let lena =
+            CanonicalPathBuf::canonicalize(root.path().join("nested/lena.jpg"))
+                .unwrap();
+        let meta = &index.path2meta[&lena];
+        let metadata = fs::metadata(&lena).unwrap();
+        assert_eq!(meta.size, metadata.len());
+        assert_eq!(meta.modified, metadata.modified().unwrap());
+        assert_eq!(meta.extension, Some("jpg".into()));
+        assert_eq!(meta.file_type, ResourceFileType::File);
+
+        let target = CanonicalPathBuf::canonicalize(&target).unwrap();
+        let link = &index.path2meta[&target];
+        assert_eq!(link.file_type, ResourceFileType::Symlink);
+        assert_eq!(link.size, 6);
+        assert_eq!(link.id, ResourceId::from_bytes(b"target"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_hidden_and_serialized() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = fixture();
+        let hidden = root
+            .path()
+            .join(OsStr::from_bytes(b".hidden\xff"));
+        let visible = root
+            .path()
+            .join(OsStr::from_bytes(b"visible\xff.txt"));
+        fs::write(&hidden, b"hidden").unwrap();
+        fs::write(&visible, b"visible").unwrap();
+
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let visible = CanonicalPathBuf::canonicalize(visible).unwrap();
+        assert_eq!(index.size(), 5);
+        assert!(index.path2meta.contains_key(&visible));
+
+        let json = serde_json::to_string(&index).unwrap();
+        let restored: ResourceIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.roots, index.roots);
+        assert_eq!(restored.path2meta, index.path2meta);
+        assert_eq!(restored.collisions, index.collisions);
+        assert_eq!(restored.ids, index.ids);
+    }
+
+    #[test]
+    fn modified_since_returns_later_entries() {
+        let root = fixture();
+        let epoch = SystemTime::UNIX_EPOCH;
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        for (name, days) in [("a.txt", 1), ("b.txt", 2), ("nested/c.txt", 3)] {
+            fs::File::options()
+                .write(true)
+                .open(root.path().join(name))
+                .unwrap()
+                .set_modified(epoch + day * days)
+                .unwrap();
+        }
+
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let mut names: Vec<_> = index
+            .modified_since(epoch + day * 2)
+            .into_iter()
+            .map(|(path, _)| path.file_name().unwrap().to_owned())
+            .collect();
+        names.sort();
+
+        // lena.jpg keeps the current time
+        assert_eq!(names, ["c.txt", "lena.jpg"]);
+    }
+
+    #[test]
+    fn resources_grouped_by_day() {
+        let root = fixture();
+        let epoch = SystemTime::UNIX_EPOCH;
+        let day = Duration::from_secs(24 * 60 * 60);
+        for name in ["a.txt", "b.txt", "nested/c.txt", "nested/lena.jpg"] {
+            // 2000-02-29 and 2000-03-01 for c.txt
+            let days = if name == "nested/c.txt" {
+                11_017
+            } else {
+                11_016
+            };
+            fs::File::options()
+                .write(true)
+                .open(root.path().join(name))
+                .unwrap()
+                .set_modified(epoch + day * days + Duration::from_secs(60))
+                .unwrap();
+        }
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let a =
+            CanonicalPathBuf::canonicalize(root.path().join("a.txt")).unwrap();
+        let b =
+            CanonicalPathBuf::canonicalize(root.path().join("b.txt")).unwrap();
+        let leap = CalendarDay {
+            year: 2000,
+            month: 2,
+            day: 29,
+        };
+        let march = CalendarDay {
+            year: 2000,
+            month: 3,
+            day: 1,
+        };
+
+        let days = index.group_by_day(false);
+        assert_eq!(days.keys().copied().collect::<Vec<_>>(), [leap, march]);
+        assert_eq!(days[&leap].len(), 3);
+        assert_eq!(days[&march].len(), 1);
+
+        index.path2meta.get_mut(&a).unwrap().created = Some(epoch);
+        index.path2meta.get_mut(&b).unwrap().created = None;
+        let days = index.group_by_day(true);
+        let first = CalendarDay {
+            year: 1970,
+            month: 1,
+            day: 1,
+        };
+        assert_eq!(days[&first], vec![&a]);
+        assert!(days[&leap].contains(&&b));
+        assert_eq!(leap.to_string(), "2000-02-29");
+        let eve = CalendarDay {
+            year: 1969,
+            month: 12,
+            day: 31,
+        };
+        assert_eq!(CalendarDay::of(epoch - day), eve);
+        assert_eq!(CalendarDay::of(epoch - Duration::from_millis(1)), eve);
+    }
+
+    #[test]
+    fn missing_resources_are_reported_and_forgotten() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        assert!(index.missing_resources().unwrap().is_empty());
+
+        fs::remove_file(root.path().join("a.txt")).unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+
+        let missing = index.missing_resources().unwrap();
+        let mut names: Vec<_> = missing
+            .iter()
+            .map(|resource| resource.path.file_name().unwrap().to_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+        assert_eq!(index.size(), 4);
+
+        fs::remove_dir_all(root.path().join("nested")).unwrap();
+        assert_eq!(index.missing_resources().unwrap().len(), 4);
+
+        let update =
+            index.forget_paths(missing.iter().map(|resource| &resource.path));
+        // a.txt shares its content with nested/c.txt
+        assert_eq!(update.deleted.len(), 1);
+        assert_eq!(index.size(), 2);
+        assert!(index.collisions.is_empty());
+    }
+
+    #[test]
+    fn pruning_only_removes_deleted_files() {
+        let root = fixture();
+        let options = IndexOptions {
+            mass_deletion_guard: Some(0.5),
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        fs::remove_file(root.path().join("a.txt")).unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        fs::write(root.path().join("nested/c.txt"), b"edited").unwrap();
+        fs::write(root.path().join("new.txt"), b"new").unwrap();
+
+        let update = index.prune_deleted().unwrap();
+        // a.txt shares its content with nested/c.txt, which isn't rehashed
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"second")])
+        );
+        assert!(update.added.is_empty());
+        assert_eq!(index.size(), 2);
+        assert!(index.collisions.is_empty());
+
+        fs::remove_dir_all(root.path().join("nested")).unwrap();
+        let err = index.prune_deleted().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ArkError>(),
+            Some(ArkError::MassDeletionBlocked { gone: 2, total: 2, update })
+                if update.deleted.len() == 2
+        ));
+        assert_eq!(index.size(), 2);
+    }
+
+    #[test]
+    fn path_and_content_counts() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        assert_eq!(index.path_count(), index.size());
+        assert_eq!(index.path_count(), 4);
+        assert_eq!(index.content_count(), index.ids.len());
+        assert_eq!(index.content_count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_cycles_terminate() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(root.path().join("a")).unwrap();
+        fs::create_dir(root.path().join("b")).unwrap();
+        fs::write(root.path().join("a/x.txt"), b"x").unwrap();
+        fs::write(root.path().join("b/y.txt"), b"y").unwrap();
+        symlink("../b", root.path().join("a/to_b")).unwrap();
+        symlink("../a", root.path().join("b/to_a")).unwrap();
+
+        let options = IndexOptions {
+            symlinks: SymlinkMode::FollowTarget,
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options.clone())
+                .unwrap();
+        assert_eq!(index.size(), 2);
+
+        let options = IndexOptions {
+            symlink_loops: SymlinkLoops::Fail,
+            ..options
+        };
+        assert!(
+            ResourceIndex::build_with_options(root.path(), options).is_err()
+        );
+    }
+
+    #[test]
+    fn build_from_piped_paths() {
+        let root = fixture();
+        let lena = root.path().join("nested/lena.jpg");
+        let input =
+            format!("a.txt\nnested/c.txt\n{}\nmissing.txt\n", lena.display());
+
+        let index = ResourceIndex::build_from_paths(
+            io::Cursor::new(input),
+            root.path().to_owned(),
+        )
+        .unwrap();
+
+        assert_eq!(index.size(), 3);
+        assert_eq!(index.content_count(), 2);
+        assert!(index
+            .path2meta
+            .contains_key(&CanonicalPathBuf::canonicalize(lena).unwrap()));
+    }
+
+    #[test]
+    fn preview_update_subtree_does_not_commit() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        fs::write(root.path().join("nested/d.txt"), b"fourth").unwrap();
+        fs::write(root.path().join("b.txt"), b"changed").unwrap();
+        fs::remove_file(root.path().join("nested/lena.jpg")).unwrap();
+
+        let preview = index
+            .preview_update_subtree(root.path().join("nested"))
+            .unwrap();
+        assert_eq!(preview.deleted.len(), 1);
+        assert_eq!(preview.added.len(), 1);
+        assert!(preview
+            .added
+            .keys()
+            .all(|path| path.ends_with("nested/d.txt")));
+        assert_eq!(index.size(), 4);
+
+        let unchanged = tempfile::Builder::new()
+            .prefix("empty")
+            .tempdir_in(root.path())
+            .unwrap();
+        assert!(index
+            .preview_update_subtree(unchanged.path())
+            .unwrap()
+            .is_empty());
+
+        let update = index.update().unwrap();
+        assert!(preview.deleted.is_subset(&update.deleted));
+        assert_eq!(index.size(), 4);
+    }
+
+    #[test]
+    fn entry_matches_snapshot() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let snapshot = index.path2meta.clone();
+        assert!(snapshot
+            .iter()
+            .all(|(path, meta)| index.entry_matches(path, meta)));
+
+        fs::write(root.path().join("b.txt"), b"changed").unwrap();
+        index.update().unwrap();
+
+        let b =
+            CanonicalPathBuf::canonicalize(root.path().join("b.txt")).unwrap();
+        let a =
+            CanonicalPathBuf::canonicalize(root.path().join("a.txt")).unwrap();
+        assert!(!index.entry_matches(&b, &snapshot[&b]));
+        assert!(index.entry_matches(&a, &snapshot[&a]));
+        assert!(!index.entry_matches(&a, &snapshot[&b]));
+    }
+
+    #[test]
+    fn duplicates_grouped_by_directory() {
+        let root = fixture();
+        fs::write(root.path().join("nested/d.txt"), b"twice").unwrap();
+        fs::write(root.path().join("nested/e.txt"), b"twice").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let root_dir = root.path().canonicalize().unwrap();
+        let nested_dir = root_dir.join("nested");
+        let groups = index.duplicates_by_directory();
+        assert_eq!(groups.len(), 2);
+
+        // a.txt and nested/c.txt
+        let scattered = &groups[&root_dir];
+        assert_eq!(scattered.len(), 1);
+        assert_eq!(scattered[0].0, ResourceId::from_bytes(b"first"));
+        assert_eq!(scattered[0].1.len(), 2);
+
+        let siblings = &groups[&nested_dir];
+        assert_eq!(siblings.len(), 1);
+        assert_eq!(siblings[0].0, ResourceId::from_bytes(b"twice"));
+    }
+
+    #[test]
+    fn partial_build_finds_candidates() {
+        let root = fixture();
+        fs::write(root.path().join("x.bin"), b"header:xxxx").unwrap();
+        fs::write(root.path().join("y.bin"), b"header:yyyy").unwrap();
+
+        let full = ResourceIndex::build(root.path()).unwrap();
+        let partial = ResourceIndex::build_partial(root.path(), 7).unwrap();
+
+        assert_eq!(full.collisions.len(), 1);
+        assert_eq!(partial.collisions.len(), 2);
+        assert_eq!(partial.size(), full.size());
+    }
+
+    #[test]
+    fn event_log_records_mutations() {
+        let root = fixture();
+        let options = IndexOptions {
+            event_log: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert_eq!(index.events().count(), 0);
+
+        let started = SystemTime::now();
+        fs::write(root.path().join("b.txt"), b"changed").unwrap();
+        index.update().unwrap();
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        index.update().unwrap();
+        let lena =
+            CanonicalPathBuf::canonicalize(root.path().join("nested/lena.jpg"))
+                .unwrap();
+        let lena_id = index.path2meta[&lena].id.clone();
+        index.forget_paths([&lena]);
+
+        let events = index.drain_events();
+        let kinds: Vec<_> = events
+            .iter()
+            .map(|event| {
+                let name = event.path.file_name().unwrap().to_owned();
+                (name, event.kind.clone())
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (
+                    "b.txt".into(),
+                    IndexEventKind::Changed {
+                        old: ResourceId::from_bytes(b"second"),
+                        new: ResourceId::from_bytes(b"changed"),
+                    }
+                ),
+                (
+                    "d.txt".into(),
+                    IndexEventKind::Added(ResourceId::from_bytes(b"fourth"))
+                ),
+                ("lena.jpg".into(), IndexEventKind::Deleted(lena_id)),
+            ]
+        );
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].at <= pair[1].at));
+        assert!(events[0].at >= started);
+        assert_eq!(index.events().count(), 0);
+    }
+
+    #[test]
+    fn event_log_is_bounded() {
+        let root = fixture();
+        let options = IndexOptions {
+            event_log: true,
+            event_log_capacity: Some(2),
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        for i in 0..5 {
+            fs::write(root.path().join(format!("{}.txt", i)), i.to_string())
+                .unwrap();
+            index.update().unwrap();
+        }
+
+        let events = index.drain_events();
+        assert_eq!(events.len(), 2);
+        assert!(events[1].path.ends_with("4.txt"));
+    }
+
+    #[test]
+    fn on_update_hook_sees_every_update() {
+        use std::sync::{Arc, Mutex};
+
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        index.on_update(move |update| {
+            sink.lock()
+                .unwrap()
+                .push((update.added.len(), update.deleted.len()))
+        });
+
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        index.update().unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        index.update().unwrap();
+        index.clear_on_update();
+        index.update().unwrap();
+
+        assert_eq!(*seen.lock().unwrap(), [(1, 0), (0, 1)]);
+        assert_eq!(index.as_update().added.len(), index.size());
+    }
+
+    #[test]
+    fn blocked_ids_are_not_indexed() {
+        let root = fixture();
+        let blocked = ResourceId::from_bytes(b"first");
+        let options = IndexOptions {
+            blocked_ids: [blocked.clone()].into(),
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        assert_eq!(index.size(), 2);
+        assert!(!index.ids().contains(&blocked));
+
+        fs::write(root.path().join("d.txt"), b"first").unwrap();
+        assert!(index.update().unwrap().is_empty());
+    }
+
+    #[test]
+    fn duplicates_grouped_by_type() {
+        let root = fixture();
+        fs::copy("./tests/lena.jpg", root.path().join("lena-copy.JPG"))
+            .unwrap();
+        fs::write(root.path().join("x.foo"), b"foo").unwrap();
+        fs::write(root.path().join("y.foo"), b"foo").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let groups = index.duplicates_by_type();
+        let mut categories: Vec<_> = groups.keys().cloned().collect();
+        categories.sort();
+        assert_eq!(categories, ["foo", "image", "text"]);
+        assert_eq!(groups["image"][0].1.len(), 2);
+    }
+
+    #[test]
+    fn duplicate_waste_is_ranked() {
+        let root = fixture();
+        let large = vec![7u8; 10_000];
+        for name in ["x.bin", "y.bin", "z.bin"] {
+            fs::write(root.path().join(name), &large).unwrap();
+        }
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        assert_eq!(
+            index.duplicate_waste_ranked(),
+            [
+                (ResourceId::from_bytes(&large), 20_000),
+                (ResourceId::from_bytes(b"first"), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn roots_filter_their_own_extensions() {
+        let pictures = fixture();
+        let documents = fixture();
+        let options = IndexOptions::default();
+        let mut index = ResourceIndex::build_with_roots(
+            vec![
+                IndexRoot::with_extensions(pictures.path(), ["JPG"]),
+                IndexRoot::with_extensions(documents.path(), ["txt"]),
+            ],
+            options,
+        )
+        .unwrap();
+
+        // lena.jpg from pictures, all three txt files from documents
+        assert_eq!(index.size(), 4);
+        assert_eq!(index.collisions.len(), 1);
+
+        fs::write(pictures.path().join("new.txt"), b"ignored").unwrap();
+        fs::write(documents.path().join("new.txt"), b"indexed").unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.added.len(), 1);
+        assert!(update
+            .added
+            .keys()
+            .all(|path| path.starts_with(documents.path())));
+
+        let json = serde_json::to_string(&index).unwrap();
+        let restored: ResourceIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.roots(), index.roots());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlinks_collapse_into_single_copy() {
+        let root = fixture();
+        let path = root.path();
+        fs::hard_link(path.join("b.txt"), path.join("nested/b.txt")).unwrap();
+        fs::hard_link(path.join("a.txt"), path.join("d.txt")).unwrap();
+
+        let plain = ResourceIndex::build(path).unwrap();
+        assert_eq!(plain.duplicates().len(), 2);
+
+        let options = IndexOptions {
+            collapse_hardlinks: true,
+            ..IndexOptions::default()
+        };
+        let index = ResourceIndex::build_with_options(path, options).unwrap();
+        assert_eq!(index.collisions, plain.collisions);
+
+        let duplicates = index.duplicates();
+        assert_eq!(duplicates.len(), 1);
+        let first = &duplicates[&ResourceId::from_bytes(b"first")];
+        assert_eq!(first.len(), 2);
+        assert_eq!(
+            index.duplicate_waste_ranked(),
+            vec![(ResourceId::from_bytes(b"first"), 5)]
+        );
+    }
+
+    #[cfg(feature = "similarity")]
+    #[test]
+    fn drafts_are_similar_documents() {
+        let root = fixture();
+        let draft = "the quick brown fox jumps over the lazy dog and then \
+            runs away into the deep dark forest where nobody can find it";
+        let edited = draft.replace("deep dark", "dark");
+        fs::write(root.path().join("draft.txt"), draft).unwrap();
+        fs::write(root.path().join("edited.txt"), &edited).unwrap();
+        fs::write(root.path().join("other.txt"), "nothing alike").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let id = ResourceId::from_bytes(draft.as_bytes());
+        let similar = index.similar_documents(&id, 0.5);
+        assert_eq!(similar.len(), 1);
+        assert!(similar[0].0.ends_with("edited.txt"));
+        assert!(similar[0].1 < 1.0);
+        let lena = index
+            .id_at(root.path().join("nested/lena.jpg"))
+            .unwrap();
+        assert!(index.similar_documents(lena, 0.0).is_empty());
+    }
+
+    #[cfg(feature = "similarity")]
+    #[test]
+    fn documents_are_shingled_while_hashed() {
+        let root = fixture();
+        let words = "one two three four five six seven eight nine ten ";
+        fs::write(root.path().join("long.txt"), words.repeat(1000)).unwrap();
+        let opened = Arc::new(Mutex::new(vec![]));
+        let sink = opened.clone();
+        let build = |partial_hash| {
+            let sink = sink.clone();
+            let options = IndexOptions {
+                partial_hash,
+                opener: Some(Opener::new(move |path| {
+                    sink.lock().unwrap().push(path.to_owned());
+                    Ok(Box::new(fs::File::open(path)?))
+                })),
+                ..IndexOptions::default()
+            };
+            ResourceIndex::build_with_options(root.path(), options).unwrap()
+        };
+
+        let full = build(None);
+        assert_eq!(opened.lock().unwrap().len(), 5);
+        let long = CanonicalPathBuf::canonicalize(root.path().join("long.txt"))
+            .unwrap();
+        let minhash = full.path2meta[&long].minhash.clone();
+        assert!(minhash.is_some());
+        // the rest of the text is read on past the prefix
+        let partial = build(Some(64));
+        assert_eq!(opened.lock().unwrap().len(), 10);
+        assert_eq!(partial.path2meta[&long].minhash, minhash);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn simulated_dedup_matches_the_deletion() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = fixture();
+        let path = root.path();
+        fs::write(path.join("d.txt"), b"first").unwrap();
+        fs::hard_link(path.join("b.txt"), path.join("nested/b.txt")).unwrap();
+        let mut index = ResourceIndex::build(path).unwrap();
+        let used = || -> u64 {
+            let mut nodes = HashSet::new();
+            WalkDir::new(path)
+                .into_iter()
+                .map(|entry| entry.unwrap().metadata().unwrap())
+                .filter(|metadata| metadata.is_file())
+                .filter(|metadata| nodes.insert(metadata.ino()))
+                .map(|metadata| metadata.len())
+                .sum()
+        };
+
+        let hardlinks = index.simulate_dedup(DedupStrategy::Hardlink);
+        assert_eq!(hardlinks.freed_bytes, 10);
+        assert_eq!(hardlinks.hardlinked.len(), 2);
+        let report = index.simulate_dedup(DedupStrategy::Delete);
+        assert_eq!(report.removed.len(), 3);
+        assert_eq!(report.survivors.len(), 2);
+
+        let before = used();
+        index
+            .resolve_collisions(true, |id, _| {
+                CollisionResolution::KeepOnly(report.survivors[id].clone())
+            })
+            .unwrap();
+        assert_eq!(before - used(), report.freed_bytes);
+        assert!(index.collisions.is_empty());
+    }
+
+    #[test]
+    fn watch_subtree_reports_inside_changes_only() {
+        let root = fixture();
+        use std::sync::{Arc, Mutex};
+
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        index
+            .watch_subtree(root.path().join("nested"), move |update| {
+                sink.lock()
+                    .unwrap()
+                    .push((update.deleted.len(), update.added.len()));
+            })
+            .unwrap();
+
+        fs::write(root.path().join("outside.txt"), b"outside").unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        index.update().unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(index.size(), 4);
+
+        fs::write(root.path().join("nested/inside.txt"), b"inside").unwrap();
+        fs::remove_file(root.path().join("nested/lena.jpg")).unwrap();
+        index.update().unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn watch_subtree_keeps_other_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let all = Arc::new(Mutex::new(0));
+        let inside = Arc::new(Mutex::new(0));
+        let (all_sink, inside_sink) = (all.clone(), inside.clone());
+        index.on_update(move |_| *all_sink.lock().unwrap() += 1);
+        let watch = index
+            .watch_subtree(root.path().join("nested"), move |_| {
+                *inside_sink.lock().unwrap() += 1
+            })
+            .unwrap();
+
+        fs::write(root.path().join("nested/inside.txt"), b"inside").unwrap();
+        index.update().unwrap();
+        assert_eq!((*all.lock().unwrap(), *inside.lock().unwrap()), (1, 1));
+
+        assert!(index.remove_hook(watch));
+        assert!(!index.remove_hook(watch));
+        fs::write(root.path().join("nested/more.txt"), b"more").unwrap();
+        index.update().unwrap();
+        assert_eq!((*all.lock().unwrap(), *inside.lock().unwrap()), (2, 1));
+    }
+
+    #[test]
+    fn file_turning_into_directory_and_back() {
+        let root = fixture();
+        let options = IndexOptions {
+            event_log: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        let toggled = root.path().join("b.txt");
+        let second = ResourceId::from_bytes(b"second");
+
+        fs::remove_file(&toggled).unwrap();
+        fs::create_dir(&toggled).unwrap();
+        fs::write(toggled.join("inner.txt"), b"second").unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.deleted, HashSet::from([second.clone()]));
+        assert_eq!(update.added.len(), 1);
+        assert!(update
+            .added
+            .keys()
+            .all(|path| path.starts_with(&toggled)));
+
+        fs::remove_dir_all(&toggled).unwrap();
+        fs::write(&toggled, b"second").unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.deleted, HashSet::from([second.clone()]));
+        assert!(update
+            .added
+            .contains_key(&CanonicalPathBuf::canonicalize(&toggled).unwrap()));
+
+        let kinds: Vec<IndexEventKind> = index
+            .drain_events()
+            .into_iter()
+            .map(|e| e.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                IndexEventKind::Deleted(second.clone()),
+                IndexEventKind::Added(second.clone()),
+                IndexEventKind::Deleted(second.clone()),
+                IndexEventKind::Added(second),
+            ]
+        );
+        assert_eq!(index.size(), 4);
+    }
+
+    #[test]
+    fn directories_are_compared_by_content() {
+        let a = fixture();
+        let b = fixture();
+        fs::write(a.path().join("only-a.txt"), b"unique to a").unwrap();
+        fs::write(b.path().join("only-b.txt"), b"unique to b").unwrap();
+        fs::write(b.path().join("b.txt"), b"changed").unwrap();
+
+        let comparison =
+            ResourceIndex::compare_dirs(a.path(), b.path()).unwrap();
+
+        assert_eq!(comparison.only_in_a, vec![PathBuf::from("only-a.txt")]);
+        assert_eq!(comparison.only_in_b, vec![PathBuf::from("only-b.txt")]);
+        assert_eq!(comparison.different, vec![PathBuf::from("b.txt")]);
+        assert_eq!(
+            comparison.same,
+            vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("nested/c.txt"),
+                PathBuf::from("nested/lena.jpg"),
+            ]
+        );
+    }
+
+    #[test]
+    fn directory_stats_aggregate_sizes() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let root_path = root.path().canonicalize().unwrap();
+        let nested = root_path.join("nested");
+        let lena = fs::metadata(nested.join("lena.jpg"))
+            .unwrap()
+            .len();
+
+        let stats = index.directory_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[&nested],
+            DirStats {
+                files: 2,
+                size: lena + 5,
+                total_files: 2,
+                total_size: lena + 5,
+            }
+        );
+        assert_eq!(
+            stats[&root_path],
+            DirStats {
+                files: 2,
+                size: 11,
+                total_files: 4,
+                total_size: lena + 16,
+            }
+        );
+
+        let largest = index.largest_directories(1);
+        assert_eq!(largest, vec![(root_path.clone(), stats[&root_path])]);
+    }
+
+    #[test]
+    fn purge_id_removes_every_copy() {
+        let root = fixture();
+        fs::write(root.path().join("d.txt"), b"first").unwrap();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+        assert_eq!(index.collisions[&first], 3);
+
+        let purged = index.purge_id(&first, true).unwrap();
+        assert_eq!(purged.len(), 3);
+        assert!(purged.iter().all(|path| !path.exists()));
+        assert!(!index.ids().contains(&first));
+        assert!(index.collisions.is_empty());
+        assert_eq!(index.size(), 2);
+
+        let second = ResourceId::from_bytes(b"second");
+        let forgotten = index.purge_id(&second, false).unwrap();
+        assert_eq!(forgotten.len(), 1);
+        assert!(forgotten[0].exists());
+        assert!(!index.ids().contains(&second));
+    }
+
+    #[test]
+    fn duplicates_are_gathered_into_one_directory() {
+        let root = fixture();
+        let review = root.path().join("review");
+        fs::create_dir(&review).unwrap();
+        fs::write(review.join("a.txt"), b"reviewed").unwrap();
+        fs::write(root.path().join("nested/a.txt"), b"first").unwrap();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        let moves = index
+            .gather_duplicates_into(&review, true)
+            .unwrap();
+        let names: Vec<String> = moves
+            .iter()
+            .map(|(_, to)| {
+                to.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(names, ["a (1).txt", "c.txt"]);
+        assert!(moves
+            .iter()
+            .all(|(from, to)| !from.exists() && to.exists()));
+        assert!(root.path().join("a.txt").exists());
+
+        let first = ResourceId::from_bytes(b"first");
+        assert_eq!(index.collisions[&first], 3);
+        let update = index.update().unwrap();
+        assert!(update.is_empty());
+        assert!(index.id_at(review.join("c.txt")).is_some());
+        assert!(index
+            .id_at(root.path().join("nested/c.txt"))
+            .is_none());
+    }
+
+    #[test]
+    fn classifier_tags_indexed_files() {
+        let root = fixture();
+        let options = IndexOptions {
+            classifier: Some(Classifier::new(|path, _| {
+                match path.as_path().extension() {
+                    Some(extension) if extension == "jpg" => {
+                        vec!["image".to_owned()]
+                    }
+                    _ => vec![],
+                }
+            })),
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        let lena = index
+            .id_at(root.path().join("nested/lena.jpg"))
+            .unwrap()
+            .clone();
+        assert_eq!(index.tags(&lena), vec!["image"]);
+        assert!(index
+            .tags(&ResourceId::from_bytes(b"first"))
+            .is_empty());
+
+        index.tag(&lena, "portrait");
+        fs::write(root.path().join("d.jpg"), b"fourth").unwrap();
+        index.update().unwrap();
+        let fourth = ResourceId::from_bytes(b"fourth");
+        assert_eq!(index.tags(&fourth), vec!["image"]);
+        assert_eq!(index.tags(&lena), vec!["image", "portrait"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        index
+            .store(dir.path().join("index.json"))
+            .unwrap();
+        let loaded =
+            ResourceIndex::load(dir.path().join("index.json")).unwrap();
+        assert_eq!(loaded.tags(&fourth), vec!["image"]);
+        assert_eq!(loaded.tags(&lena), vec!["image", "portrait"]);
+    }
+
+    #[test]
+    fn samples_are_reproducible() {
+        let root = fixture();
+        for i in 0..20 {
+            let path = root.path().join(format!("{}.txt", i));
+            fs::write(path, i.to_string()).unwrap();
+        }
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let sample = index.sample(5, 42);
+        assert_eq!(sample.len(), 5);
+        assert_eq!(index.sample(5, 42), sample);
+        assert_ne!(index.sample(5, 43), sample);
+        assert_eq!(index.sample(100, 42).len(), index.size());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unchanged_directories_skip_the_walk() {
+        let root = fixture();
+        let options = IndexOptions {
+            trust_dir_mtimes: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        let past = SystemTime::UNIX_EPOCH + Duration::from_secs(60);
+        for dir in [root.path().to_owned(), root.path().join("nested")] {
+            fs::File::open(dir)
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        }
+        // directories touched just before the build aren't trusted
+        assert!(index.update().unwrap().is_empty());
+
+        // an in-place edit leaves the directory alone, so goes unnoticed
+        let a = root.path().join("a.txt");
+        fs::write(&a, b"first, edited").unwrap();
+        let newer = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(newer)
+            .unwrap();
+        assert!(index.update().unwrap().is_empty());
+
+        fs::write(root.path().join("nested/d.txt"), b"fourth").unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.added.len(), 2);
+        assert_eq!(update.modified_in_place.len(), 1);
+        assert_eq!(index.size(), 5);
+    }
+
+    #[test]
+    fn tags_are_carried_over_edits() {
+        let root = fixture();
+        let options = IndexOptions {
+            carry_metadata_on_edit: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+        let second = ResourceId::from_bytes(b"second");
+        index.tag(&first, "draft");
+        index.tag(&second, "final");
+
+        let a = root.path().join("a.txt");
+        fs::write(&a, b"first, edited").unwrap();
+        let newer = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&a)
+            .unwrap()
+            .set_modified(newer)
+            .unwrap();
+        index.update().unwrap();
+        let edited = ResourceId::from_bytes(b"first, edited");
+        assert_eq!(index.tags(&edited), vec!["draft"]);
+        // nested/c.txt still holds the old content
+        assert_eq!(index.tags(&first), vec!["draft"]);
+
+        fs::write(root.path().join("b.txt"), b"second, edited").unwrap();
+        index
+            .replace_content(root.path().join("b.txt"))
+            .unwrap();
+        let edited = ResourceId::from_bytes(b"second, edited");
+        assert_eq!(index.tags(&edited), vec!["final"]);
+        assert!(index.tags(&second).is_empty());
+
+        // carried tags are stored, and the loaded index carries on
+        let dir = tempfile::tempdir().unwrap();
+        index
+            .store(dir.path().join("index.json"))
+            .unwrap();
+        let mut loaded =
+            ResourceIndex::load(dir.path().join("index.json")).unwrap();
+        assert_eq!(loaded.tags(&edited), vec!["final"]);
+        fs::write(root.path().join("b.txt"), b"second, final").unwrap();
+        loaded
+            .replace_content(root.path().join("b.txt"))
+            .unwrap();
+        let last = ResourceId::from_bytes(b"second, final");
+        assert_eq!(loaded.tags(&last), vec!["final"]);
+    }
+
+    #[test]
+    fn collisions_are_resolved_by_the_callback() {
+        let root = fixture();
+        fs::write(root.path().join("d.txt"), b"first").unwrap();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+        let kept =
+            CanonicalPathBuf::canonicalize(root.path().join("d.txt")).unwrap();
+
+        let removed = index
+            .resolve_collisions(true, |id, paths| {
+                assert_eq!(id, &first);
+                assert_eq!(paths.len(), 3);
+                CollisionResolution::KeepOnly(kept.clone())
+            })
+            .unwrap();
+        assert_eq!(removed.len(), 2);
+        assert!(removed.iter().all(|path| !path.exists()));
+        assert!(kept.exists());
+        assert!(index.collisions.is_empty());
+        assert_eq!(index.path2meta[&kept].id, first);
+        assert_eq!(index.size(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_modes() {
+        use std::os::unix::fs::symlink;
+
+        let root = fixture();
+        let outside = fixture();
+        symlink("a.txt", root.path().join("link.txt")).unwrap();
+        symlink(outside.path().join("b.txt"), root.path().join("out.txt"))
+            .unwrap();
+        let build = |symlinks| {
+            let options = IndexOptions {
+                symlinks,
+                ..IndexOptions::default()
+            };
+            ResourceIndex::build_with_options(root.path(), options).unwrap()
+        };
+
+        assert_eq!(build(SymlinkMode::Skip).size(), 4);
+
+        // the inner link resolves to an indexed file
+        let followed = build(SymlinkMode::FollowTarget);
+        assert_eq!(followed.size(), 5);
+        assert!(followed.path2meta.keys().any(|path| {
+            path.starts_with(outside.path().canonicalize().unwrap())
+        }));
+
+        let mut links = build(SymlinkMode::HashLinkTarget);
+        assert_eq!(links.size(), 6);
+        let (link, meta) = links
+            .path2meta
+            .iter()
+            .find(|(path, _)| path.as_path().ends_with("link.txt"))
+            .unwrap();
+        assert_eq!(meta.id, ResourceId::from_bytes(b"a.txt"));
+        assert_eq!(meta.file_type, ResourceFileType::Symlink);
+
+        fs::rename(link, root.path().join("nested/moved.txt")).unwrap();
+        let update = links.update().unwrap();
+        let id = ResourceId::from_bytes(b"a.txt");
+        assert_eq!(update.deleted, HashSet::from([id]));
+        assert_eq!(update.added.len(), 1);
+    }
+
+    #[test]
+    fn oldest_and_newest_entries() {
+        use std::time::Duration;
+
+        let root = fixture();
+        for (name, secs) in [
+            ("a.txt", 3000),
+            ("b.txt", 1000),
+            ("nested/c.txt", 4000),
+            ("nested/lena.jpg", 2000),
+        ] {
+            let file = fs::File::options()
+                .write(true)
+                .open(root.path().join(name))
+                .unwrap();
+            file.set_modified(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            )
+            .unwrap();
+        }
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        for meta in index.path2meta.values_mut() {
+            meta.created = None;
+        }
+
+        let (oldest, _) = index.oldest().unwrap();
+        let (newest, _) = index.newest().unwrap();
+        assert!(oldest.ends_with("b.txt"));
+        assert!(newest.ends_with("nested/c.txt"));
+        assert_eq!(
+            index.date_range(),
+            Some((
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1000),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(4000),
+            ))
+        );
+
+        // creation time is preferred where known
+        let a =
+            CanonicalPathBuf::canonicalize(root.path().join("a.txt")).unwrap();
+        let lena =
+            CanonicalPathBuf::canonicalize(root.path().join("nested/lena.jpg"))
+                .unwrap();
+        index.path2meta.get_mut(&a).unwrap().created =
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(500));
+        index.path2meta.get_mut(&lena).unwrap().created =
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(5000));
+        assert_eq!(index.oldest().unwrap().0, &a);
+        assert_eq!(index.newest().unwrap().0, &lena);
+        assert_eq!(
+            index.date_range(),
+            Some((
+                SystemTime::UNIX_EPOCH + Duration::from_secs(500),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(5000),
+            ))
+        );
+
+        let empty = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        let index = ResourceIndex::build(empty.path()).unwrap();
+        assert!(index.oldest().is_none());
+        assert!(index.newest().is_none());
+        assert!(index.date_range().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn directories_are_identified_by_inode() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir_all(root.path().join("real/inner")).unwrap();
+        fs::write(root.path().join("real/inner/x.txt"), b"x").unwrap();
+        symlink("real", root.path().join("alias")).unwrap();
+        symlink("../real", root.path().join("real/inner/up")).unwrap();
+
+        let key = |path: PathBuf| {
+            let entry = WalkDir::new(path)
+                .follow_links(true)
+                .into_iter()
+                .next()
+                .unwrap()
+                .unwrap();
+            dir_key(&entry).unwrap()
+        };
+        assert_eq!(
+            key(root.path().join("real")),
+            key(root.path().join("alias"))
+        );
+
+        let options = IndexOptions {
+            symlinks: SymlinkMode::FollowTarget,
+            ..IndexOptions::default()
+        };
+        let entries = discover_paths(root.path(), &options, None).unwrap();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn replace_content_moves_path_to_new_id() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+        let transcoded = ResourceId::from_bytes(b"transcoded");
+        assert_eq!(index.collisions[&first], 2);
+
+        let path = root.path().join("a.txt");
+        fs::write(&path, b"transcoded").unwrap();
+        let change = index.replace_content(&path).unwrap();
+
+        assert_eq!(
+            change,
+            ContentChange {
+                old: first.clone(),
+                new: transcoded.clone(),
+            }
+        );
+        assert!(change.changed());
+        assert!(!index.collisions.contains_key(&first));
+        assert!(index.ids().contains(&first));
+        assert!(index.ids().contains(&transcoded));
+        assert_eq!(index.size(), 4);
+
+        let unchanged = index.replace_content(&path).unwrap();
+        assert!(!unchanged.changed());
+        assert!(index
+            .replace_content(root.path().join("missing.txt"))
+            .is_err());
+    }
+
+    #[test]
+    fn parallel_scan_builds_identical_index() {
+        let root = fixture();
+        let serial = ResourceIndex::build(root.path()).unwrap();
+        for parallelism in [Parallelism::Auto, Parallelism::Fixed(3)] {
+            let options = IndexOptions {
+                parallelism,
+                ..IndexOptions::default()
+            };
+            let parallel =
+                ResourceIndex::build_with_options(root.path(), options)
+                    .unwrap();
+            assert_eq!(parallel.path2meta, serial.path2meta);
+            assert_eq!(parallel.collisions, serial.collisions);
+        }
+    }
+
+    #[test]
+    fn restore_rolls_back_update() {
+        let root = fixture();
+        let options = IndexOptions {
+            event_log: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        let checkpoint = index.checkpoint();
+
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        index.update().unwrap();
+        assert!(index
+            .ids()
+            .contains(&ResourceId::from_bytes(b"fourth")));
+        assert_eq!(index.events().count(), 2);
+
+        index.restore(checkpoint.clone());
+        assert!(index
+            .ids()
+            .contains(&ResourceId::from_bytes(b"second")));
+        assert!(!index
+            .ids()
+            .contains(&ResourceId::from_bytes(b"fourth")));
+        assert_eq!(index.path2meta, checkpoint.path2meta);
+        assert_eq!(index.events().count(), 0);
+
+        // the disk still differs, so the same changes show up again
+        assert_eq!(index.update().unwrap().added.len(), 1);
+    }
+
+    #[test]
+    fn update_tells_edits_in_place_apart() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        fs::write(root.path().join("b.txt"), b"edited").unwrap();
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        fs::rename(root.path().join("a.txt"), root.path().join("e.txt"))
+            .unwrap();
+        let update = index.update().unwrap();
+
+        let edited =
+            CanonicalPathBuf::canonicalize(root.path().join("b.txt")).unwrap();
+        assert_eq!(
+            update.modified_in_place,
+            HashMap::from([(
+                edited,
+                (
+                    ResourceId::from_bytes(b"second"),
+                    ResourceId::from_bytes(b"edited")
+                )
+            )])
+        );
+        assert_eq!(update.added.len(), 2);
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"second")])
+        );
+    }
+
+    #[test]
+    fn paths_by_id_matching_glob() {
+        let root = fixture();
+        fs::create_dir_all(root.path().join("other/deep")).unwrap();
+        fs::write(root.path().join("other/deep/d.txt"), b"first").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+
+        let matching = index.paths_by_id_matching(&first, "nested/*");
+        assert_eq!(matching.len(), 1);
+        assert!(matching[0].ends_with("nested/c.txt"));
+
+        assert_eq!(index.paths_by_id_matching(&first, "*.txt").len(), 1);
+        assert_eq!(
+            index
+                .paths_by_id_matching(&first, "**/*.txt")
+                .len(),
+            3
+        );
+        assert_eq!(
+            index
+                .paths_by_id_matching(&first, "other/**")
+                .len(),
+            1
+        );
+        assert_eq!(index.paths_by_id_matching(&first, "?.txt").len(), 1);
+        assert!(index
+            .paths_by_id_matching(&first, "*.jpg")
+            .is_empty());
+    }
+
+    #[test]
+    fn streams_are_hashed_apart_from_files() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        let id = index.add_reader("stdin", &b"first"[..]).unwrap();
+        assert_eq!(id, ResourceId::from_bytes(b"first"));
+        assert_eq!(index.stream("stdin"), Some(&id));
+        assert!(index.ids().contains(&id));
+        assert_eq!(index.size(), 4);
+
+        index.update().unwrap();
+        assert_eq!(index.stream("stdin"), Some(&id));
+        assert!(index.stream("other").is_none());
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        };
+        let root = fixture();
+        let path = root.path().join("a.txt");
+
+        let mut failures = 2;
+        let canonicalized = policy.retry(|| {
+            if failures > 0 {
+                failures -= 1;
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "glitch"));
+            }
+            CanonicalPathBuf::canonicalize(&path)
+        });
+        assert!(canonicalized.is_ok());
+        assert_eq!(failures, 0);
+
+        let mut calls = 0;
+        let missing = policy.retry(|| {
+            calls += 1;
+            CanonicalPathBuf::canonicalize(root.path().join("missing"))
+        });
+        assert!(missing.is_err());
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let persistent: io::Result<()> = policy.retry(|| {
+            calls += 1;
+            Err(io::Error::new(io::ErrorKind::TimedOut, "down"))
+        });
+        assert!(persistent.is_err());
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn id_at_looks_up_paths() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        assert_eq!(
+            index.id_at(root.path().join("nested/../b.txt")),
+            Some(&ResourceId::from_bytes(b"second"))
+        );
+        assert!(index
+            .id_at(root.path().join("missing.txt"))
+            .is_none());
+        assert!(index.id_at(root.path().join("nested")).is_none());
+    }
+
+    #[test]
+    fn hidden_exceptions_are_indexed() {
+        let root = fixture();
+        fs::write(root.path().join(".keep"), b"kept").unwrap();
+        fs::write(root.path().join(".other"), b"other").unwrap();
+        let options = IndexOptions {
+            hidden_exceptions: HashSet::from([".keep".to_owned()]),
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        assert_eq!(index.size(), 5);
+        assert!(index.id_at(root.path().join(".keep")).is_some());
+        assert!(index.id_at(root.path().join(".other")).is_none());
+    }
+
+    #[test]
+    fn hidden_fn_replaces_the_dot_rule() {
+        let root = fixture();
+        fs::write(root.path().join(".env"), b"env").unwrap();
+        let options = IndexOptions {
+            hidden_fn: Some(HiddenFilter::new(|entry: &DirEntry| {
+                entry.file_name() == "nested"
+            })),
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        assert_eq!(index.size(), 3);
+        assert!(index.id_at(root.path().join(".env")).is_some());
+        assert!(index
+            .id_at(root.path().join("nested/c.txt"))
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_report_lists_every_excluded_path() {
+        let root = fixture();
+        fs::write(root.path().join(".hidden"), b"hidden").unwrap();
+        fs::write(root.path().join("empty.txt"), b"").unwrap();
+        let broken = root.path().join("broken");
+        std::os::unix::fs::symlink(root.path().join("gone"), &broken).unwrap();
+        let options = IndexOptions {
+            symlinks: SymlinkMode::FollowTarget,
+            ..IndexOptions::default()
+        };
+
+        let (index, report) =
+            ResourceIndex::build_with_report(root.path(), options).unwrap();
+        assert_eq!(index.size(), 4);
+        assert_eq!(report.filtered_count(Exclusion::Hidden), 1);
+        assert_eq!(report.filtered_count(Exclusion::Empty), 1);
+        assert_eq!(report.filtered.len(), 2);
+        assert_eq!(report.unreadable.len(), 1);
+        assert_eq!(report.unreadable[0].0, broken);
+        assert!(report.unscannable.is_empty());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn trailing_dot_names_are_excluded_or_preserved() {
+        let root = fixture();
+        // only the extended-length form keeps the trailing dot
+        let verbatim = fs::canonicalize(root.path()).unwrap();
+        fs::write(verbatim.join("draft.txt."), b"draft").unwrap();
+        fs::write(verbatim.join("notes "), b"notes").unwrap();
+
+        let (index, report) = ResourceIndex::build_with_report(
+            root.path(),
+            IndexOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(index.size(), 4);
+        assert_eq!(report.filtered_count(Exclusion::TrailingDotName), 2);
+
+        let options = IndexOptions {
+            trailing_dot_names: TrailingDotNames::Preserve,
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert_eq!(index.size(), 6);
+        let draft = CanonicalPathBuf::canonicalize(verbatim.join("draft.txt."))
+            .unwrap();
+        assert_eq!(
+            index.path2meta[&draft].id,
+            ResourceId::from_bytes(b"draft")
+        );
+    }
+
+    #[test]
+    fn filesystem_root_is_refused() {
+        let root = std::env::current_dir()
+            .unwrap()
+            .ancestors()
+            .last()
+            .unwrap()
+            .to_owned();
+        let err = ResourceIndex::build(&root).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ArkError>(),
+            Some(&ArkError::SystemRoot(root))
+        );
+        if cfg!(target_os = "linux") {
+            assert!(ResourceIndex::build("/proc").is_err());
+            assert!(is_pseudo_filesystem(Path::new("/sys")));
+        }
+    }
+
+    #[test]
+    fn unavailable_root_is_not_mass_deletion() {
+        let parent = fixture();
+        let root = parent.path().join("volume");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.txt"), b"first").unwrap();
+        let mut index = ResourceIndex::build(&root).unwrap();
+
+        fs::rename(&root, parent.path().join("unmounted")).unwrap();
+        let err = index.update().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ArkError>(),
+            Some(&ArkError::RootMissing(root.clone()))
+        );
+        assert!(index.missing_resources().is_err());
+        assert_eq!(index.size(), 1);
+
+        fs::write(&root, b"not a directory").unwrap();
+        let err = index.update().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ArkError>(),
+            Some(&ArkError::RootUnavailable(root.clone()))
+        );
+        assert_eq!(index.size(), 1);
+
+        fs::remove_file(&root).unwrap();
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("b.txt"), b"second").unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.deleted.len(), 1);
+        assert_eq!(update.added.len(), 1);
+    }
+
+    #[test]
+    fn mass_deletion_is_refused() {
+        let root = fixture();
+        let options = IndexOptions {
+            mass_deletion_guard: Some(0.5),
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        fs::remove_file(root.path().join("a.txt")).unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+        fs::remove_file(root.path().join("nested/c.txt")).unwrap();
+        let err = index.update().unwrap_err();
+        match err.downcast_ref::<ArkError>() {
+            Some(ArkError::MassDeletionBlocked {
+                gone,
+                total,
+                update,
+            }) => {
+                assert_eq!((*gone, *total), (3, 4));
+                assert_eq!(update.deleted.len(), 2);
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(index.size(), 4);
+
+        let update = index.update_force().unwrap();
+        assert_eq!(update.deleted.len(), 2);
+        assert_eq!(index.size(), 1);
+    }
+
+    #[test]
+    fn content_is_verified_despite_mtime() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        let path = root.path().join("a.txt");
+        let lie = |content: &[u8]| {
+            fs::write(&path, content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(60))
+                .unwrap();
+        };
+        lie(b"first");
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        lie(b"other");
+        assert!(index.update().unwrap().is_empty());
+        index.mark_suspicious(&path).unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.modified_in_place.len(), 1);
+        assert!(index.suspicious.is_empty());
+
+        let options = IndexOptions {
+            verify_content_on_update: true,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert!(index.update().unwrap().is_empty());
+        lie(b"third");
+        let update = index.update().unwrap();
+        let id = ResourceId::from_bytes(b"third");
+        assert_eq!(update.added.values().next().unwrap().id, id);
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"other")])
+        );
+    }
+
+    #[test]
+    fn resources_grouped_by_directory() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+
+        let groups: Vec<(PathBuf, Vec<PathBuf>)> = index
+            .group_by_directory()
+            .map(|(directory, resources)| {
+                let paths = resources
+                    .into_iter()
+                    .map(|(path, _)| path.as_path().to_path_buf())
+                    .collect();
+                (directory.to_path_buf(), paths)
+            })
+            .collect();
+        assert_eq!(
+            groups,
+            vec![
+                (root.clone(), vec![root.join("a.txt"), root.join("b.txt")]),
+                (
+                    root.join("nested"),
+                    vec![
+                        root.join("nested/c.txt"),
+                        root.join("nested/lena.jpg")
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn summary_describes_the_index() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let summary = index.summary_json();
+
+        assert_eq!(summary["files"], 4);
+        assert_eq!(summary["unique"], 3);
+        let total: u64 = index
+            .path2meta
+            .values()
+            .map(|meta| meta.size)
+            .sum();
+        assert_eq!(summary["total_bytes"], total);
+        assert_eq!(summary["duplicate_bytes"], 5);
+        assert_eq!(summary["top_extensions"][0], serde_json::json!(["txt", 3]));
+        assert_eq!(summary["top_extensions"][1], serde_json::json!(["jpg", 1]));
+        assert!(summary["last_updated"].is_u64());
+        assert_eq!(summary["errors"], 0);
+        assert_eq!(summary.as_object().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn batch_is_rolled_back_when_transactional() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let before = index.path2meta.clone();
+
+        fs::write(root.path().join("a.txt"), b"changed").unwrap();
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        let outside = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempfile()
+            .unwrap();
+        let batch = [
+            root.path().join("a.txt"),
+            outside.path().to_path_buf(),
+            root.path().join("d.txt"),
+        ];
+
+        assert!(index.apply_changes(batch.iter(), true).is_err());
+        assert_eq!(index.path2meta, before);
+
+        let update = index.apply_changes(batch.iter(), false).unwrap();
+        assert_eq!(update.added.len(), 2);
+        assert_eq!(update.modified_in_place.len(), 1);
+        assert!(update.deleted.is_empty());
+        assert_eq!(index.size(), 5);
+
+        fs::remove_file(root.path().join("d.txt")).unwrap();
+        let update = index
+            .apply_changes([root.path().join("d.txt")], true)
+            .unwrap();
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"fourth")])
+        );
+        assert_eq!(index.size(), 4);
+
+        // as in `update`, a file truncated to nothing is removed
+        fs::write(root.path().join("b.txt"), b"").unwrap();
+        let update = index
+            .apply_changes([root.path().join("b.txt")], true)
+            .unwrap();
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"second")])
+        );
+        assert_eq!(index.size(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hashed_links_are_looked_up_at_their_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = fixture();
+        let link = root.path().join("link");
+        let target = root.path().join("a.txt");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let options = IndexOptions {
+            symlinks: SymlinkMode::HashLinkTarget,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        let id = ResourceId::from_bytes(target.as_os_str().as_bytes());
+        assert_eq!(index.id_at(&link), Some(&id));
+        assert_eq!(
+            index.id_at(&target),
+            Some(&ResourceId::from_bytes(b"first"))
+        );
+        let meta = index.get_meta_owned(&link).unwrap();
+        assert_eq!(meta.file_type, ResourceFileType::Symlink);
+        index.mark_suspicious(&link).unwrap();
+        assert!(index.update().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn batch_keeps_hashed_links_at_their_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = fixture();
+        let link = root.path().join("link");
+        std::os::unix::fs::symlink(root.path().join("a.txt"), &link).unwrap();
+        let options = IndexOptions {
+            symlinks: SymlinkMode::HashLinkTarget,
+            ..IndexOptions::default()
+        };
+        let mut index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+
+        fs::remove_file(&link).unwrap();
+        let target = root.path().join("b.txt");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let update = index.apply_changes([&link], true).unwrap();
+        assert_eq!(update.modified_in_place.len(), 1);
+        let (key, meta) = update.added.iter().next().unwrap();
+        assert_eq!(key.file_name(), link.file_name());
+        assert_eq!(meta.file_type, ResourceFileType::Symlink);
+        assert_eq!(
+            meta.id,
+            ResourceId::from_bytes(target.as_os_str().as_bytes())
+        );
+        assert_eq!(index.size(), 5);
+    }
+
+    #[test]
+    fn partial_copies_are_paired() {
+        let root = fixture();
+        let content: Vec<u8> =
+            (0..20_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(root.path().join("full.bin"), &content).unwrap();
+        fs::write(root.path().join("cut.bin"), &content[..9_000]).unwrap();
+        let mut other = content[..9_000].to_vec();
+        other[8_000] ^= 1;
+        fs::write(root.path().join("other.bin"), &other).unwrap();
+
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let root = fs::canonicalize(root.path()).unwrap();
+        let pairs: Vec<(PathBuf, PathBuf)> = index
+            .partial_copies()
+            .into_iter()
+            .map(|(partial, complete)| {
+                (partial.into_path_buf(), complete.into_path_buf())
+            })
+            .collect();
+        assert_eq!(pairs, vec![(root.join("cut.bin"), root.join("full.bin"))]);
+    }
+
+    #[test]
+    fn resources_are_selected_by_copy_count() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let path = |name: &str| {
+            CanonicalPathBuf::canonicalize(root.path().join(name)).unwrap()
+        };
+
+        assert_eq!(
+            index.resources_with_copy_count(2),
+            vec![(
+                ResourceId::from_bytes(b"first"),
+                vec![path("a.txt"), path("nested/c.txt")]
+            )]
+        );
+        let singles = index.resources_with_copy_count(1);
+        assert_eq!(singles.len(), 2);
+        assert_eq!(singles[0].1, vec![path("b.txt")]);
+        assert_eq!(singles[1].1, vec![path("nested/lena.jpg")]);
+        assert!(index.resources_with_copy_count(0).is_empty());
+        assert!(index.resources_with_copy_count(3).is_empty());
+    }
+
+    #[test]
+    fn resources_are_tagged_by_query() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+
+        let tagged = index.tag_matching(|_, meta| meta.size > 5, "big");
+        assert_eq!(tagged, 2);
+        let lena = index
+            .id_at(root.path().join("nested/lena.jpg"))
+            .unwrap();
+        assert_eq!(index.tags(lena), vec!["big"]);
+        let second = ResourceId::from_bytes(b"second");
+        assert!(!index.tag(&second, "big"));
+        assert!(index.tag(&second, "text"));
+        assert_eq!(index.tags(&second), vec!["big", "text"]);
+        assert!(index
+            .tags(&ResourceId::from_bytes(b"first"))
+            .is_empty());
+
+        let json = serde_json::to_string(&index).unwrap();
+        let loaded: ResourceIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.tags(&second), vec!["big", "text"]);
+        assert_eq!(loaded.tag_map(), index.tag_map());
+    }
+
+    #[test]
+    fn estimate_matches_the_build() {
+        let root = fixture();
+        fs::write(root.path().join("empty.txt"), b"").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("a.txt", root.path().join("link.txt"))
+            .unwrap();
+
+        for symlinks in [SymlinkMode::Skip, SymlinkMode::HashLinkTarget] {
+            let options = IndexOptions {
+                symlinks,
+                ..IndexOptions::default()
+            };
+            let estimate =
+                ResourceIndex::estimate(root.path(), &options).unwrap();
+            let index = ResourceIndex::build_with_options(root.path(), options)
+                .unwrap();
+            assert_eq!(estimate.files, index.size());
+            let bytes: u64 = index
+                .path2meta
+                .values()
+                .map(|meta| meta.size)
+                .sum();
+            assert_eq!(estimate.bytes, bytes);
+        }
+    }
+
+    #[test]
+    fn logger_receives_progress() {
+        let root = fixture();
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        let options = IndexOptions {
+            logger: Some(LogSink::new(move |level, message: &str| {
+                sink.lock()
+                    .unwrap()
+                    .push((level, message.to_owned()))
+            })),
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert!(messages
+            .lock()
+            .unwrap()
+            .contains(&(Level::Info, "Index built".to_owned())));
+
+        let messages = Arc::new(Mutex::new(vec![]));
+        let sink = messages.clone();
+        let mut index = index.with_logger(move |_, message| {
+            sink.lock().unwrap().push(message.to_owned())
+        });
+        index.update().unwrap();
+        assert_eq!(messages.lock().unwrap()[0], "Updating the index");
+    }
+
+    #[test]
+    fn empty_files_follow_the_policy() {
+        let root = fixture();
+        fs::write(root.path().join("empty.txt"), b"").unwrap();
+        fs::write(root.path().join("empty.jpg"), b"").unwrap();
+        let build = |empty_file_handling| {
+            let options = IndexOptions {
+                empty_file_handling,
+                ..IndexOptions::default()
+            };
+            ResourceIndex::build_with_options(root.path(), options).unwrap()
+        };
+
+        let index = build(EmptyHandling::Skip);
+        assert_eq!(index.size(), 4);
+        assert_eq!(index.scan_errors(), 0);
+
+        let index = build(EmptyHandling::SharedId);
+        assert_eq!(index.size(), 6);
+        assert_eq!(index.collisions[&ResourceId::from_bytes(b"")], 2);
+
+        let mut index = build(EmptyHandling::DistinctById);
+        assert_eq!(index.size(), 6);
+        assert_eq!(index.content_count(), 5);
+        assert!(!index.ids().contains(&ResourceId::from_bytes(b"")));
+        let id = index
+            .id_at(root.path().join("empty.txt"))
+            .cloned();
+        assert!(index.update().unwrap().is_empty());
+        assert_eq!(
+            index
+                .id_at(root.path().join("empty.txt"))
+                .cloned(),
+            id
+        );
+    }
+
+    #[test]
+    fn deleted_root_is_reported_as_missing() {
+        let parent = fixture();
+        let root = parent.path().join("volume");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.txt"), b"first").unwrap();
+        fs::write(root.join("b.txt"), b"second").unwrap();
+        let mut index = ResourceIndex::build(&root).unwrap();
+
+        fs::remove_dir_all(&root).unwrap();
+        let err = index.update().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ArkError>(),
+            Some(&ArkError::RootMissing(root.clone()))
+        );
+        assert_eq!(index.size(), 2);
+
+        fs::create_dir(&root).unwrap();
+        let update = index.update().unwrap();
+        assert_eq!(update.deleted.len(), 2);
+        assert!(index.path2meta.is_empty());
+    }
+
+    #[test]
+    fn owned_results_cross_threads() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let meta = index
+            .get_meta_owned(root.path().join("b.txt"))
+            .unwrap();
+        let duplicates = index.duplicates_owned();
+        drop(index);
+        let (size, copies) = std::thread::spawn(move || {
+            (
+                meta.size,
+                duplicates[&ResourceId::from_bytes(b"first")].len(),
+            )
+        })
+        .join()
+        .unwrap();
+        assert_eq!((size, copies), (6, 2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cached_ids_are_reused_while_unchanged() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        let cache = tempfile::tempdir().unwrap();
+        let cache = cache.path().join("checksums.json");
+        let write = |name: &str, content: &[u8]| {
+            let path = root.path().join(name);
+            fs::write(&path, content).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(60))
+                .unwrap();
+        };
+        write("a.txt", b"first");
+        write("b.txt", b"second");
+        let first =
+            ResourceIndex::build_with_cache(root.path(), &cache).unwrap();
+        assert!(cache.exists());
+
+        // same size and mtime, so only a re-hash would notice
+        write("a.txt", b"other");
+        write("b.txt", b"thirds");
+        let second =
+            ResourceIndex::build_with_cache(root.path(), &cache).unwrap();
+        assert_eq!(second.path2meta, first.path2meta);
+
+        fs::write(root.path().join("b.txt"), b"thirds").unwrap();
+        let third =
+            ResourceIndex::build_with_cache(root.path(), &cache).unwrap();
+        assert_eq!(
+            third.id_at(root.path().join("a.txt")),
+            Some(&ResourceId::from_bytes(b"first"))
+        );
+        assert_eq!(
+            third.id_at(root.path().join("b.txt")),
+            Some(&ResourceId::from_bytes(b"thirds"))
+        );
+    }
+
+    #[test]
+    fn duplicates_with_differing_extensions_only() {
+        let root = fixture();
+        fs::write(root.path().join("d.TXT"), b"second").unwrap();
+        fs::copy("./tests/lena.jpg", root.path().join("lena.png")).unwrap();
+        fs::copy("./tests/lena.jpg", root.path().join("lena")).unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let differing = index.duplicates_with_differing_extensions();
+        assert_eq!(differing.len(), 1);
+        let paths = differing.values().next().unwrap();
+        assert_eq!(paths.len(), 3);
+        assert!(paths
+            .iter()
+            .any(|path| path.ends_with("lena.png")));
+        assert_eq!(index.duplicates().len(), 3);
+    }
+
+    #[test]
+    fn light_update_carries_ids_only() {
+        let root = fixture();
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        fs::write(root.path().join("d.txt"), b"fourth").unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+
+        let update = index.update_light().unwrap();
+        let d =
+            CanonicalPathBuf::canonicalize(root.path().join("d.txt")).unwrap();
+        let fourth = ResourceId::from_bytes(b"fourth");
+        assert_eq!(update.added, HashMap::from([(d, fourth)]));
+        assert_eq!(
+            update.deleted,
+            HashSet::from([ResourceId::from_bytes(b"second")])
+        );
+        assert!(index.update_light().unwrap().added.is_empty());
+    }
+
+    #[test]
+    fn sync_status_splits_the_ids() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let second = ResourceId::from_bytes(b"second");
+        let remote = ResourceId::from_bytes(b"remote");
+        let other_ids = HashSet::from([second.clone(), remote.clone()]);
+
+        let status = index.sync_status(&other_ids);
+        assert_eq!(status.only_here.len(), 2);
+        assert_eq!(status.only_there, vec![remote]);
+        assert_eq!(status.in_both, vec![second]);
+        let lena = fs::metadata(root.path().join("nested/lena.jpg")).unwrap();
+        assert_eq!(status.only_here_bytes, 5 + lena.len());
+    }
+
+    #[test]
+    fn transfer_plan_sends_missing_content_once() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let remote = HashSet::from([ResourceId::from_bytes(b"second")]);
+
+        let plan = index.transfer_plan(&remote);
+        let names = |paths: Vec<&CanonicalPathBuf>| -> Vec<String> {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let path = path.as_path().strip_prefix(root.path());
+                    path.unwrap().display().to_string()
+                })
+                .collect()
+        };
+        assert_eq!(
+            names(plan.send.iter().map(|(path, _)| path).collect()),
+            ["a.txt", "nested/lena.jpg"]
+        );
+        assert_eq!(
+            names(plan.moves.iter().collect()),
+            ["b.txt", "nested/c.txt"]
+        );
+        let lena = fs::metadata(root.path().join("nested/lena.jpg")).unwrap();
+        assert_eq!(plan.bytes, 5 + lena.len());
+        assert_eq!(index.transfer_plan(index.ids()).bytes, 0);
+    }
+
+    #[test]
+    fn entries_in_dir_are_not_recursive() {
+        let root = fixture();
+        fs::create_dir(root.path().join("nested/deeper")).unwrap();
+        fs::write(root.path().join("nested/deeper/d.txt"), b"deep").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let names = |dir: PathBuf| -> Vec<String> {
+            index
+                .entries_in_dir(dir)
+                .into_iter()
+                .map(|(path, _)| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect()
+        };
+        assert_eq!(names(root.path().to_path_buf()), vec!["a.txt", "b.txt"]);
+        assert_eq!(
+            names(root.path().join("nested")),
+            vec!["c.txt", "lena.jpg"]
+        );
+        assert_eq!(names(root.path().join("nested/deeper")), vec!["d.txt"]);
+        assert!(names(root.path().join("absent")).is_empty());
+    }
+
+    #[test]
+    fn open_files_are_limited() {
+        use std::sync::atomic::AtomicUsize;
+
+        /// Counts itself as open until dropped.
+        struct Counted(fs::File, Arc<(AtomicUsize, AtomicUsize)>);
+
+        impl Read for Counted {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.1 .0.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let root = fixture();
+        for i in 0..20 {
+            fs::write(root.path().join(format!("{}.txt", i)), i.to_string())
+                .unwrap();
+        }
+        let build = |max_open_files| {
+            let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+            let opened = counts.clone();
+            let options = IndexOptions {
+                parallelism: Parallelism::Fixed(8),
+                max_open_files,
+                opener: Some(Opener::new(move |path| {
+                    let open = opened.0.fetch_add(1, Ordering::SeqCst) + 1;
+                    opened.1.fetch_max(open, Ordering::SeqCst);
+                    let file = Counted(fs::File::open(path)?, opened.clone());
+                    // keeps files open long enough for threads to overlap
+                    std::thread::sleep(Duration::from_millis(5));
+                    Ok(Box::new(file))
+                })),
+                ..IndexOptions::default()
+            };
+            let index = ResourceIndex::build_with_options(root.path(), options)
+                .unwrap();
+            assert_eq!(counts.0.load(Ordering::SeqCst), 0);
+            (index, counts.1.load(Ordering::SeqCst))
+        };
+
+        let (limited, peak) = build(Some(2));
+        assert_eq!(limited.size(), 24);
+        assert!((1..=2).contains(&peak), "{} files open at once", peak);
+        let (index, peak) = build(None);
+        assert!(peak > 2, "{} files open at once", peak);
+        assert_eq!(limited.path2meta, index.path2meta);
+    }
+
+    #[test]
+    fn resources_sorted_by_several_keys() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let names = |keys: &[SortKey]| -> Vec<String> {
+            index
+                .sorted_by(keys)
+                .into_iter()
+                .map(|resource| {
+                    resource
+                        .path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into()
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            names(&[
+                SortKey::Extension(SortOrder::Descending),
+                SortKey::Size(SortOrder::Descending),
+            ]),
+            vec!["b.txt", "a.txt", "c.txt", "lena.jpg"]
+        );
+        assert_eq!(
+            names(&[SortKey::Size(SortOrder::Ascending)]),
+            vec!["a.txt", "c.txt", "b.txt", "lena.jpg"]
+        );
+        assert_eq!(names(&[]), vec!["a.txt", "b.txt", "c.txt", "lena.jpg"]);
+    }
+
+    #[test]
+    fn filter_iter_is_lazy() {
+        use std::cell::Cell;
+
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let mut matching: Vec<&CanonicalPathBuf> = index
+            .filter_iter(|_, meta| meta.id == ResourceId::from_bytes(b"first"))
+            .map(|(path, _)| path)
+            .collect();
+        matching.sort();
+        assert_eq!(matching.len(), 2);
+        assert!(matching[0].ends_with("a.txt"));
+        assert!(matching[1].ends_with("nested/c.txt"));
+
+        let calls = Cell::new(0);
+        let first = index
+            .filter_iter(|_, _| {
+                calls.set(calls.get() + 1);
+                true
+            })
+            .next();
+        assert!(first.is_some());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Error;
+use canonical_path::CanonicalPathBuf;
+
+use crate::id::ResourceId;
+use crate::index::{discover_paths, EmptyHandling, IndexOptions};
+use crate::meta::link_target;
+
+/// Path attributes known right after discovery, the id
+/// is computed only when asked for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LazyResourceMeta {
+    pub size: u64,
+    pub modified: SystemTime,
+    id: Option<ResourceId>,
+    link: bool,
+}
+
+impl LazyResourceMeta {
+    /// The id, if it was computed already.
+    pub fn id(&self) -> Option<&ResourceId> {
+        self.id.as_ref()
+    }
+}
+
+/// Index which is built without reading any file. Ids and collisions
+/// appear as files are hashed by `id`, so only the content actually
+/// queried is ever read.
+#[derive(Debug)]
+pub struct LazyResourceIndex {
+    pub path2meta: HashMap<CanonicalPathBuf, LazyResourceMeta>,
+    pub collisions: HashMap<ResourceId, usize>,
+    ids: HashSet<ResourceId>,
+    root: PathBuf,
+    options: IndexOptions,
+}
+
+impl LazyResourceIndex {
+    pub fn build<P: AsRef<Path>>(root_path: P) -> Result<Self, Error> {
+        Self::build_with_options(root_path, IndexOptions::default())
+    }
+
+    pub fn build_with_options<P: AsRef<Path>>(
+        root_path: P,
+        options: IndexOptions,
+    ) -> Result<Self, Error> {
+        log::info!("Creating a lazy index");
+
+        let mut path2meta = HashMap::new();
+        for (path, entry) in discover_paths(&root_path, &options, None)? {
+            // unfollowed links are described by themselves
+            let link = entry.file_type().is_symlink();
+            let metadata = if entry.path_is_symlink() && !link {
+                fs::metadata(&path)
+            } else {
+                entry.metadata().map_err(Into::into)
+            };
+            match metadata {
+                Ok(metadata)
+                    if metadata.len() > 0
+                        || options.empty_file_handling
+                            != EmptyHandling::Skip =>
+                {
+                    let meta = LazyResourceMeta {
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                        id: None,
+                        link,
+                    };
+                    path2meta.insert(path, meta);
+                }
+                Ok(_) => {}
+                Err(msg) => log::error!(
+                    "Couldn't retrieve metadata for {}: {}",
+                    path.display(),
+                    msg
+                ),
+            }
+        }
+
+        Ok(LazyResourceIndex {
+            path2meta,
+            collisions: HashMap::new(),
+            ids: HashSet::new(),
+            root: root_path.as_ref().to_path_buf(),
+            options,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Ids computed so far.
+    pub fn ids(&self) -> &HashSet<ResourceId> {
+        &self.ids
+    }
+
+    /// The id of the file at `path`, hashing it on first access.
+    /// Files with blocked content are dropped from the index.
+    pub fn id(&mut self, path: &CanonicalPathBuf) -> Result<ResourceId, Error> {
+        let meta = self.path2meta.get_mut(path).ok_or_else(|| {
+            Error::msg(format!("{} is not indexed", path.display()))
+        })?;
+        if let Some(id) = meta.id.as_ref() {
+            return Ok(id.clone());
+        }
+
+        log::trace!("Calculating hash of {}", path.display());
+        let distinct_empty =
+            self.options.empty_file_handling == EmptyHandling::DistinctById;
+        let id = if meta.link {
+            ResourceId::from_bytes(&link_target(path.as_path())?)
+        } else if meta.size == 0 && distinct_empty {
+            ResourceId::of_empty_at(path.as_path())
+        } else {
+            let source = fs::File::open(path)?;
+            match self.options.partial_hash {
+                Some(prefix_len) => ResourceId::partial_from_reader(
+                    meta.size, source, prefix_len,
+                )?,
+                None => ResourceId::from_sized_reader(meta.size, source)?,
+            }
+        };
+        if self.options.blocked_ids.contains(&id) {
+            self.path2meta.remove(path);
+            return Err(Error::msg(format!(
+                "{} has blocked content",
+                path.display()
+            )));
+        }
+        meta.id = Some(id.clone());
+
+        if !self.ids.insert(id.clone()) {
+            *self.collisions.entry(id.clone()).or_insert(1) += 1;
+        }
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_computed_on_demand() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("b.txt"), b"first").unwrap();
+        let a =
+            CanonicalPathBuf::canonicalize(root.path().join("a.txt")).unwrap();
+        let b =
+            CanonicalPathBuf::canonicalize(root.path().join("b.txt")).unwrap();
+
+        let mut index = LazyResourceIndex::build(root.path()).unwrap();
+        assert_eq!(index.path2meta.len(), 2);
+        assert!(index.ids().is_empty());
+        assert_eq!(index.path2meta[&a].size, 5);
+        assert!(index.path2meta[&a].id().is_none());
+
+        let expected = ResourceId::from_bytes(b"first");
+        assert_eq!(index.id(&a).unwrap(), expected);
+        assert_eq!(index.path2meta[&a].id(), Some(&expected));
+        assert!(index.path2meta[&b].id().is_none());
+        assert!(index.collisions.is_empty());
+
+        assert_eq!(index.id(&b).unwrap(), expected);
+        assert_eq!(index.id(&b).unwrap(), expected);
+        assert_eq!(index.collisions[&expected], 2);
+    }
+}
@@ -2,9 +2,23 @@
 extern crate lazy_static;
 extern crate canonical_path;
 
-mod id;
-mod index;
-mod meta;
+pub mod atomic;
+mod cache;
+pub mod collections;
+mod encoding;
+pub mod error;
+pub mod events;
+pub mod id;
+pub mod index;
+pub mod lazy;
+pub mod meta;
+#[cfg(feature = "similarity")]
+mod similarity;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod store;
+pub mod tree;
+pub mod verify;
 
 use index::ResourceIndex;
 
@@ -15,7 +29,6 @@ use std::sync::{Arc, RwLock};
 use canonical_path::CanonicalPathBuf;
 
 use anyhow::Error;
-use log;
 
 pub const TAG_STORAGE_FILENAME: &str = ".ark-tags";
 
@@ -48,5 +61,5 @@ pub fn provide_index<P: AsRef<Path>>(
     registrar.insert(canonical_path, arc.clone());
 
     log::info!("Index was registered");
-    return Ok(arc);
+    Ok(arc)
 }
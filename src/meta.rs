@@ -1,19 +1,70 @@
+use crate::encoding;
+use crate::error::ArkError;
 use crate::id::ResourceId;
+use crate::index::{EmptyHandling, IndexOptions};
 
 use anyhow::Error;
 use canonical_path::CanonicalPathBuf;
+use serde::{Deserialize, Serialize};
 use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Cursor, Read};
 use std::time::SystemTime;
 use walkdir::DirEntry;
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct ResourceMeta {
     pub id: ResourceId,
+    pub size: u64,
     pub modified: SystemTime,
+    /// Creation time, where the platform and filesystem record it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<SystemTime>,
+    pub file_type: ResourceFileType,
+    #[serde(with = "encoding::option_os_str")]
     pub name: Option<OsString>,
+    #[serde(with = "encoding::option_os_str")]
     pub extension: Option<OsString>,
     pub kind: Option<ResourceKind>,
     pub extra: Option<ResourceExtra>,
+    /// Device and inode of the file, shared by its hardlinks.
+    /// Captured on Unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<FileNode>,
+    /// Whether the beginning of the file looks like UTF-8 text,
+    /// see `is_probably_text`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<bool>,
+    /// MinHash signature of the words of text files, captured with
+    /// the `similarity` feature, see `ResourceIndex::similar_documents`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub minhash: Option<Vec<u64>>,
+}
+
+impl ResourceMeta {
+    /// Whether the file is text rather than binary, guessed from its
+    /// first bytes: no zero bytes and valid UTF-8. `None` when the
+    /// file wasn't sampled, e.g. in indexes stored by older versions.
+    pub fn is_probably_text(&self) -> Option<bool> {
+        self.text
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FileNode {
+    pub device: u64,
+    pub inode: u64,
+}
+
+/// What is learned by reading a file: its id, whether it is text
+/// and the MinHash signature of its words.
+pub(crate) type Digest = (ResourceId, Option<bool>, Option<Vec<u64>>);
+
+/// A resource together with its location.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Resource {
+    pub path: CanonicalPathBuf,
+    pub meta: ResourceMeta,
 }
 
 impl ResourceMeta {
@@ -21,20 +72,59 @@ impl ResourceMeta {
         path: CanonicalPathBuf,
         entry: DirEntry,
     ) -> Result<(CanonicalPathBuf, Self), Error> {
+        Self::scan_with_options(path, entry, &IndexOptions::default())
+    }
+
+    pub fn scan_with_options(
+        path: CanonicalPathBuf,
+        entry: DirEntry,
+        options: &IndexOptions,
+    ) -> Result<(CanonicalPathBuf, Self), Error> {
+        Self::scan_reusing(path, entry, options, |_, _, _| None)
+    }
+
+    /// Same as `scan_with_options`, but the file isn't read when `known`
+    /// recognizes it by its node, size and modification time, and
+    /// returns what reading it would give.
+    pub(crate) fn scan_reusing<F>(
+        path: CanonicalPathBuf,
+        entry: DirEntry,
+        options: &IndexOptions,
+        known: F,
+    ) -> Result<(CanonicalPathBuf, Self), Error>
+    where
+        F: Fn(FileNode, u64, SystemTime) -> Option<Digest>,
+    {
         if entry.file_type().is_dir() {
             return Err(Error::msg("DirEntry is directory"));
         }
+        if entry.file_type().is_symlink() {
+            return Self::scan_link(path, entry);
+        }
+
+        // single stat feeding every attribute, symlinks are
+        // described by their target but remembered as links
+        let (metadata, file_type) = if entry.path_is_symlink() {
+            (fs::metadata(&path)?, ResourceFileType::Symlink)
+        } else {
+            (entry.metadata()?, ResourceFileType::File)
+        };
 
-        let metadata = entry.metadata()?;
         let size = metadata.len();
-        if size == 0 {
-            return Err(Error::msg("Empty resource"));
+        let empty_files = options.empty_file_handling;
+        if size == 0 && empty_files == EmptyHandling::Skip {
+            return Err(ArkError::EmptyResource(path.into_path_buf()).into());
         }
 
-        let id = ResourceId::compute(size, &path);
+        let modified = metadata.modified()?;
+        let inode = file_node(&metadata);
+        let reused = inode.and_then(|node| known(node, size, modified));
+        let (id, text, minhash) = match reused {
+            Some(reused) => reused,
+            None => Self::hash(&path, size, options)?,
+        };
         let name = convert_str(path.file_name());
         let extension = convert_str(path.extension());
-        let modified = metadata.modified()?;
 
         //todo
         let kind = None;
@@ -42,24 +132,225 @@ impl ResourceMeta {
 
         let meta = ResourceMeta {
             id,
+            size,
             modified,
+            created: metadata.created().ok(),
+            file_type,
             name,
             extension,
             kind,
             extra,
+            inode,
+            text,
+            minhash,
         };
 
         Ok((path.clone(), meta))
     }
+
+    /// Id and MinHash signature of a text file, shingled
+    /// from the bytes read for the id.
+    #[cfg(feature = "similarity")]
+    fn hash_text<R: Read>(
+        path: &CanonicalPathBuf,
+        source: R,
+        size: u64,
+        options: &IndexOptions,
+    ) -> io::Result<(ResourceId, Option<Vec<u64>>)> {
+        let mut tee = crate::similarity::TextTee::new(source);
+        let id = content_id(&mut tee, size, options)?;
+        let minhash = tee.signature().unwrap_or_else(|err| {
+            log::warn!("Couldn't shingle {}: {}", path.display(), err);
+            None
+        });
+        Ok((id, minhash))
+    }
+
+    #[cfg(not(feature = "similarity"))]
+    fn hash_text<R: Read>(
+        _path: &CanonicalPathBuf,
+        source: R,
+        size: u64,
+        options: &IndexOptions,
+    ) -> io::Result<(ResourceId, Option<Vec<u64>>)> {
+        Ok((content_id(source, size, options)?, None))
+    }
+
+    /// Id, text guess and signature of the file, read once.
+    fn hash(
+        path: &CanonicalPathBuf,
+        size: u64,
+        options: &IndexOptions,
+    ) -> Result<Digest, Error> {
+        log::trace!("Calculating hash of {}", path.display());
+        let mut source = options.retry.retry(|| match &options.opener {
+            Some(opener) => opener.open(path.as_path()),
+            None => Ok(Box::new(fs::File::open(path)?) as Box<dyn Read + Send>),
+        })?;
+        let mut sample = Vec::with_capacity(TEXT_SAMPLE as usize);
+        (&mut source)
+            .take(TEXT_SAMPLE)
+            .read_to_end(&mut sample)?;
+        let text = Some(looks_like_text(&sample, size));
+
+        // the sample is hashed too, so the file is read once
+        let source = Cursor::new(sample).chain(source);
+        let (id, minhash) = match text {
+            Some(true) => Self::hash_text(path, source, size, options)?,
+            _ => (content_id(source, size, options)?, None),
+        };
+        let empty_files = options.empty_file_handling;
+        if size == 0 && empty_files == EmptyHandling::DistinctById {
+            let id = ResourceId::of_empty_at(path.as_path());
+            return Ok((id, text, minhash));
+        }
+        Ok((id, text, minhash))
+    }
+
+    /// Describes a symlink which isn't followed, its target
+    /// path being the content.
+    fn scan_link(
+        path: CanonicalPathBuf,
+        entry: DirEntry,
+    ) -> Result<(CanonicalPathBuf, Self), Error> {
+        let metadata = entry.metadata()?;
+        let target = link_target(entry.path())?;
+        let size = target.len() as u64;
+
+        let meta = ResourceMeta {
+            id: ResourceId::from_bytes(&target),
+            size,
+            modified: metadata.modified()?,
+            created: metadata.created().ok(),
+            file_type: ResourceFileType::Symlink,
+            name: convert_str(path.file_name()),
+            extension: convert_str(path.extension()),
+            kind: None,
+            extra: None,
+            inode: file_node(&metadata),
+            text: Some(looks_like_text(&target, size)),
+            minhash: None,
+        };
+        Ok((path, meta))
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ResourceFileType {
+    File,
+    Symlink,
 }
 
 //todo
 pub type ResourceKind = ();
 pub type ResourceExtra = ();
 
+#[cfg(unix)]
+pub(crate) fn link_target(link: &std::path::Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(fs::read_link(link)?.into_os_string().into_vec())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn link_target(link: &std::path::Path) -> io::Result<Vec<u8>> {
+    Ok(fs::read_link(link)?
+        .to_string_lossy()
+        .into_owned()
+        .into_bytes())
+}
+
+const TEXT_SAMPLE: u64 = 4 * 1024;
+
+/// Id of the content, partial if `IndexOptions::partial_hash` is set.
+fn content_id<R: Read>(
+    source: R,
+    size: u64,
+    options: &IndexOptions,
+) -> io::Result<ResourceId> {
+    match options.partial_hash {
+        Some(prefix_len) => {
+            ResourceId::partial_from_reader(size, source, prefix_len)
+        }
+        None => ResourceId::from_sized_reader(size, source),
+    }
+}
+
+fn looks_like_text(sample: &[u8], file_size: u64) -> bool {
+    if sample.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        // a character cut by the end of the sample
+        Err(e) => e.error_len().is_none() && file_size > sample.len() as u64,
+    }
+}
+
+#[cfg(unix)]
+fn file_node(metadata: &fs::Metadata) -> Option<FileNode> {
+    use std::os::unix::fs::MetadataExt;
+    Some(FileNode {
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    })
+}
+
+#[cfg(not(unix))]
+fn file_node(_metadata: &fs::Metadata) -> Option<FileNode> {
+    None
+}
+
 fn convert_str(option: Option<&OsStr>) -> Option<OsString> {
     if let Some(value) = option {
         return Some(value.to_os_string());
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use walkdir::WalkDir;
+
+    fn scan(path: &std::path::Path) -> ResourceMeta {
+        let entry = WalkDir::new(path)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap();
+        let path = CanonicalPathBuf::canonicalize(path).unwrap();
+        ResourceMeta::scan(path, entry).unwrap().1
+    }
+
+    #[test]
+    fn text_files_are_told_from_binary() {
+        let dir = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        let text = dir.path().join("notes.txt");
+        fs::write(&text, "zażółć gęślą jaźń\n".repeat(1000)).unwrap();
+
+        assert_eq!(scan(&text).is_probably_text(), Some(true));
+        assert_eq!(
+            scan(std::path::Path::new("./tests/lena.jpg")).is_probably_text(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn creation_time_is_captured_where_supported() {
+        let dir = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, b"first").unwrap();
+
+        let created = fs::metadata(&path).unwrap().created().ok();
+        assert_eq!(scan(&path).created, created);
+        if cfg!(any(target_os = "macos", windows)) {
+            assert!(created.is_some());
+        }
+    }
+}
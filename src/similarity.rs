@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+use std::io::{self, Read};
+
+/// Length of the signatures, the error of the estimated
+/// similarity is about `1 / sqrt(SIGNATURE_LEN)`.
+const SIGNATURE_LEN: u64 = 64;
+
+/// Words per shingle.
+const SHINGLE_WORDS: usize = 3;
+
+/// Only the beginning of bigger documents is compared.
+const MAX_TEXT: u64 = 4 * 1024 * 1024;
+
+/// Reader keeping a copy of the text passed through, so a file is
+/// shingled from the same read which hashes it.
+pub(crate) struct TextTee<R> {
+    source: R,
+    text: Vec<u8>,
+}
+
+impl<R: Read> TextTee<R> {
+    pub(crate) fn new(source: R) -> Self {
+        TextTee {
+            source,
+            text: Vec::new(),
+        }
+    }
+
+    /// Signature of the text read so far, reading on if the hashing
+    /// stopped short of `MAX_TEXT`, e.g. at the partial hash prefix.
+    pub(crate) fn signature(mut self) -> io::Result<Option<Vec<u64>>> {
+        let missing = MAX_TEXT - self.text.len() as u64;
+        (&mut self.source)
+            .take(missing)
+            .read_to_end(&mut self.text)?;
+        Ok(signature_of(&self.text))
+    }
+}
+
+impl<R: Read> Read for TextTee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.source.read(buf)?;
+        let room = MAX_TEXT as usize - self.text.len();
+        self.text
+            .extend_from_slice(&buf[..read.min(room)]);
+        Ok(read)
+    }
+}
+
+/// MinHash signature of the word shingles of a text,
+/// `None` for texts without a single shingle.
+fn signature_of(bytes: &[u8]) -> Option<Vec<u64>> {
+    let text = String::from_utf8_lossy(bytes).to_lowercase();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let shingles: HashSet<u64> = words
+        .windows(SHINGLE_WORDS.min(words.len().max(1)))
+        .map(|shingle| fnv1a(shingle.join(" ").as_bytes()))
+        .collect();
+    if shingles.is_empty() {
+        return None;
+    }
+
+    let signature = (0..SIGNATURE_LEN)
+        .map(|seed| {
+            shingles
+                .iter()
+                .map(|shingle| mix(shingle ^ mix(seed)))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect();
+    Some(signature)
+}
+
+/// Estimated Jaccard similarity of the shingles behind the signatures.
+pub(crate) fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    let same = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    same as f64 / a.len() as f64
+}
+
+/// Stable across versions and platforms, unlike `DefaultHasher`,
+/// since signatures are stored with the index.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Finalizer of SplitMix64, spreading every input bit.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,599 @@
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::SystemTime;
+
+use anyhow::Error;
+use serde_json::Value;
+
+use crate::encoding::{raw_bytes, EncodedOsStr};
+use crate::id::ResourceId;
+use crate::index::ResourceIndex;
+use crate::store::IndexStore;
+
+/// Schema changes, each one bringing the database from the version
+/// of its position to the next one. The version is `user_version`.
+/// `path` has no type, it holds text or the raw bytes of names which
+/// aren't UTF-8.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE resources (
+        path PRIMARY KEY,
+        id TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime INTEGER NOT NULL,
+        extension TEXT,
+        meta TEXT NOT NULL
+    );",
+    "CREATE TABLE tags (
+        id TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (id, tag)
+    );
+    CREATE INDEX resources_by_id ON resources (id);
+    CREATE INDEX resources_by_size ON resources (size);
+    CREATE INDEX resources_by_mtime ON resources (mtime);",
+];
+
+/// Index stored in an SQLite database, so applications can query it
+/// with SQL, e.g. joined with their own tables. Every path is a row
+/// of `resources`: its id as formatted by `ResourceId`, size, `mtime`
+/// in nanoseconds since the epoch, extension and the whole meta as
+/// JSON. Tags are rows of `tags`. Saving rewrites the rows in a single
+/// transaction. The schema is migrated when the database is opened,
+/// databases written by a newer version are refused.
+///
+/// Requires the system SQLite library.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    path: PathBuf,
+}
+
+impl SqliteStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        SqliteStore { path: path.into() }
+    }
+
+    fn open(&self) -> Result<Connection, Error> {
+        let db = Connection::open(&self.path)?;
+        migrate(&db)?;
+        Ok(db)
+    }
+}
+
+impl IndexStore for SqliteStore {
+    fn save(&mut self, index: &ResourceIndex) -> Result<(), Error> {
+        log::info!("Storing the index to {}", self.path.display());
+
+        // roots, options and the like, the rest has tables of its own
+        let mut settings = serde_json::to_value(index)?;
+        if let Some(fields) = settings.as_object_mut() {
+            fields.remove("resources");
+            fields.remove("tags");
+        }
+
+        let db = self.open()?;
+        db.transaction(|db| {
+            db.execute("DELETE FROM resources; DELETE FROM tags;")?;
+            let mut put = db.prepare(
+                "INSERT INTO resources (path, id, size, mtime, extension, meta)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (path, meta) in index.path2meta.iter() {
+                put.bind_path(1, path.as_path())?;
+                put.bind_text(2, &meta.id.to_string())?;
+                put.bind_int(3, meta.size as i64)?;
+                put.bind_int(4, nanos_since_epoch(meta.modified))?;
+                match meta.extension.as_ref() {
+                    Some(extension) => {
+                        put.bind_text(5, &extension.to_string_lossy())?
+                    }
+                    None => put.bind_null(5)?,
+                }
+                put.bind_text(6, &serde_json::to_string(meta)?)?;
+                put.run()?;
+            }
+
+            let mut tag =
+                db.prepare("INSERT INTO tags (id, tag) VALUES (?1, ?2)")?;
+            for (id, tags) in index.tag_map() {
+                for name in tags {
+                    tag.bind_text(1, &id.to_string())?;
+                    tag.bind_text(2, name)?;
+                    tag.run()?;
+                }
+            }
+
+            let mut setting = db.prepare(
+                "INSERT OR REPLACE INTO settings (key, value)
+                VALUES ('index', ?1)",
+            )?;
+            setting.bind_text(1, &settings.to_string())?;
+            setting.run()
+        })?;
+        index.mark_clean();
+        Ok(())
+    }
+
+    fn load(&self) -> Result<ResourceIndex, Error> {
+        log::info!("Loading the index from {}", self.path.display());
+
+        let db = self.open()?;
+        let mut settings = {
+            let mut select =
+                db.prepare("SELECT value FROM settings WHERE key = 'index'")?;
+            if !select.step()? {
+                return Err(Error::msg(format!(
+                    "No index stored in {}",
+                    self.path.display()
+                )));
+            }
+            serde_json::from_str::<Value>(&select.text(0)?)?
+        };
+
+        let mut resources = vec![];
+        let mut rows = db.prepare("SELECT path, meta FROM resources")?;
+        while rows.step()? {
+            let path = match rows.column_type(0) {
+                ffi::SQLITE_BLOB => EncodedOsStr::Raw {
+                    base64: base64::encode(rows.blob(0)),
+                },
+                _ => EncodedOsStr::Utf8(rows.text(0)?),
+            };
+            let meta: Value = serde_json::from_str(&rows.text(1)?)?;
+            resources
+                .push(Value::Array(vec![serde_json::to_value(path)?, meta]));
+        }
+
+        let mut tags: BTreeMap<ResourceId, Vec<String>> = BTreeMap::new();
+        let mut rows = db.prepare("SELECT id, tag FROM tags")?;
+        while rows.step()? {
+            let id = rows.text(0)?.parse()?;
+            tags.entry(id).or_default().push(rows.text(1)?);
+        }
+        let tags: Vec<(ResourceId, Vec<String>)> = tags.into_iter().collect();
+
+        let fields = settings.as_object_mut().ok_or_else(|| {
+            Error::msg(format!("Malformed settings in {}", self.path.display()))
+        })?;
+        fields.insert("resources".into(), Value::Array(resources));
+        fields.insert("tags".into(), serde_json::to_value(tags)?);
+        let index: ResourceIndex = serde_json::from_value(settings)?;
+        index.mark_clean();
+        Ok(index)
+    }
+}
+
+/// Applies the migrations the database hasn't seen yet.
+fn migrate(db: &Connection) -> Result<(), Error> {
+    let version = db.user_version()?;
+    if version > MIGRATIONS.len() as i64 {
+        return Err(Error::msg(format!(
+            "Database schema version {} is newer than the supported {}",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate() {
+        if (from as i64) < version {
+            continue;
+        }
+        log::info!("Migrating the database to schema version {}", from + 1);
+        db.transaction(|db| {
+            db.execute(migration)?;
+            db.execute(&format!("PRAGMA user_version = {}", from + 1))
+        })?;
+    }
+    Ok(())
+}
+
+/// Negative for times before the epoch.
+fn nanos_since_epoch(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(after) => after.as_nanos() as i64,
+        Err(err) => -(err.duration().as_nanos() as i64),
+    }
+}
+
+#[cfg(unix)]
+fn c_path(path: &Path) -> Result<CString, Error> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+#[cfg(not(unix))]
+fn c_path(path: &Path) -> Result<CString, Error> {
+    let utf8 = path.to_str().ok_or_else(|| {
+        Error::msg(format!("{} is not valid UTF-8", path.display()))
+    })?;
+    Ok(CString::new(utf8)?)
+}
+
+/// Open database, closed when dropped.
+struct Connection(*mut ffi::Sqlite3);
+
+impl Connection {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let name = c_path(path)?;
+        let mut raw = ptr::null_mut();
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let code = unsafe {
+            ffi::sqlite3_open_v2(name.as_ptr(), &mut raw, flags, ptr::null())
+        };
+        // a handle is allocated even when opening fails
+        let db = Connection(raw);
+        if code != ffi::SQLITE_OK {
+            return Err(db.error(&format!("Couldn't open {}", path.display())));
+        }
+        Ok(db)
+    }
+
+    fn error(&self, context: &str) -> Error {
+        let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.0)) };
+        Error::msg(format!("{}: {}", context, message.to_string_lossy()))
+    }
+
+    /// Runs one or more statements without results.
+    fn execute(&self, sql: &str) -> Result<(), Error> {
+        let sql = CString::new(sql)?;
+        let code = unsafe {
+            ffi::sqlite3_exec(
+                self.0,
+                sql.as_ptr(),
+                None,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(self.error("Couldn't execute SQL"));
+        }
+        Ok(())
+    }
+
+    fn prepare(&self, sql: &str) -> Result<Statement<'_>, Error> {
+        let sql = CString::new(sql)?;
+        let mut raw = ptr::null_mut();
+        let code = unsafe {
+            ffi::sqlite3_prepare_v2(
+                self.0,
+                sql.as_ptr(),
+                -1,
+                &mut raw,
+                ptr::null_mut(),
+            )
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(self.error("Couldn't prepare SQL"));
+        }
+        Ok(Statement { db: self, raw })
+    }
+
+    /// Commits the changes made by `changes`, or none if it fails.
+    fn transaction<F>(&self, changes: F) -> Result<(), Error>
+    where
+        F: FnOnce(&Self) -> Result<(), Error>,
+    {
+        self.execute("BEGIN")?;
+        match changes(self) {
+            Ok(()) => self.execute("COMMIT"),
+            Err(err) => {
+                if let Err(rollback) = self.execute("ROLLBACK") {
+                    log::error!("{}", rollback);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn user_version(&self) -> Result<i64, Error> {
+        let mut pragma = self.prepare("PRAGMA user_version")?;
+        pragma.step()?;
+        Ok(pragma.int(0))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_close(self.0);
+        }
+    }
+}
+
+/// Prepared statement, finalized when dropped. Columns are
+/// numbered from 0, parameters from 1.
+struct Statement<'c> {
+    db: &'c Connection,
+    raw: *mut ffi::Stmt,
+}
+
+impl Statement<'_> {
+    fn check(&self, code: c_int) -> Result<(), Error> {
+        if code != ffi::SQLITE_OK {
+            return Err(self.db.error("Couldn't bind a parameter"));
+        }
+        Ok(())
+    }
+
+    fn bind_text(&mut self, index: c_int, text: &str) -> Result<(), Error> {
+        let code = unsafe {
+            ffi::sqlite3_bind_text(
+                self.raw,
+                index,
+                text.as_ptr() as *const c_char,
+                text.len() as c_int,
+                ffi::SQLITE_TRANSIENT,
+            )
+        };
+        self.check(code)
+    }
+
+    fn bind_blob(&mut self, index: c_int, blob: &[u8]) -> Result<(), Error> {
+        let code = unsafe {
+            ffi::sqlite3_bind_blob(
+                self.raw,
+                index,
+                blob.as_ptr() as *const c_void,
+                blob.len() as c_int,
+                ffi::SQLITE_TRANSIENT,
+            )
+        };
+        self.check(code)
+    }
+
+    /// Text if the path is valid UTF-8, raw bytes otherwise.
+    fn bind_path(&mut self, index: c_int, path: &Path) -> Result<(), Error> {
+        match path.to_str() {
+            Some(utf8) => self.bind_text(index, utf8),
+            None => self.bind_blob(index, &raw_bytes(path.as_os_str())),
+        }
+    }
+
+    fn bind_int(&mut self, index: c_int, value: i64) -> Result<(), Error> {
+        let code = unsafe { ffi::sqlite3_bind_int64(self.raw, index, value) };
+        self.check(code)
+    }
+
+    fn bind_null(&mut self, index: c_int) -> Result<(), Error> {
+        let code = unsafe { ffi::sqlite3_bind_null(self.raw, index) };
+        self.check(code)
+    }
+
+    /// Moves to the next row, false when there is none.
+    fn step(&mut self) -> Result<bool, Error> {
+        match unsafe { ffi::sqlite3_step(self.raw) } {
+            ffi::SQLITE_ROW => Ok(true),
+            ffi::SQLITE_DONE => Ok(false),
+            _ => Err(self.db.error("Couldn't execute SQL")),
+        }
+    }
+
+    /// Executes a statement without results, so it can be run again
+    /// with other parameters.
+    fn run(&mut self) -> Result<(), Error> {
+        self.step()?;
+        unsafe {
+            ffi::sqlite3_reset(self.raw);
+        }
+        Ok(())
+    }
+
+    fn column_type(&self, column: c_int) -> c_int {
+        unsafe { ffi::sqlite3_column_type(self.raw, column) }
+    }
+
+    fn blob(&self, column: c_int) -> Vec<u8> {
+        unsafe {
+            let data = ffi::sqlite3_column_blob(self.raw, column);
+            let len = ffi::sqlite3_column_bytes(self.raw, column);
+            if data.is_null() {
+                return vec![];
+            }
+            std::slice::from_raw_parts(data as *const u8, len as usize).to_vec()
+        }
+    }
+
+    fn text(&self, column: c_int) -> Result<String, Error> {
+        let bytes = unsafe {
+            let data = ffi::sqlite3_column_text(self.raw, column);
+            let len = ffi::sqlite3_column_bytes(self.raw, column);
+            if data.is_null() {
+                return Ok(String::new());
+            }
+            std::slice::from_raw_parts(data, len as usize).to_vec()
+        };
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    fn int(&self, column: c_int) -> i64 {
+        unsafe { ffi::sqlite3_column_int64(self.raw, column) }
+    }
+}
+
+impl Drop for Statement<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_finalize(self.raw);
+        }
+    }
+}
+
+/// The part of the SQLite C interface used by the store.
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_uchar, c_void};
+
+    pub enum Sqlite3 {}
+    pub enum Stmt {}
+
+    pub const SQLITE_OK: c_int = 0;
+    pub const SQLITE_ROW: c_int = 100;
+    pub const SQLITE_DONE: c_int = 101;
+    pub const SQLITE_BLOB: c_int = 4;
+    pub const SQLITE_OPEN_READWRITE: c_int = 0x02;
+    pub const SQLITE_OPEN_CREATE: c_int = 0x04;
+    /// Destructor telling SQLite to copy bound values.
+    pub const SQLITE_TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        pub fn sqlite3_open_v2(
+            filename: *const c_char,
+            db: *mut *mut Sqlite3,
+            flags: c_int,
+            vfs: *const c_char,
+        ) -> c_int;
+        pub fn sqlite3_close(db: *mut Sqlite3) -> c_int;
+        pub fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+        pub fn sqlite3_exec(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            callback: Option<
+                unsafe extern "C" fn(
+                    *mut c_void,
+                    c_int,
+                    *mut *mut c_char,
+                    *mut *mut c_char,
+                ) -> c_int,
+            >,
+            argument: *mut c_void,
+            error: *mut *mut c_char,
+        ) -> c_int;
+        pub fn sqlite3_prepare_v2(
+            db: *mut Sqlite3,
+            sql: *const c_char,
+            len: c_int,
+            statement: *mut *mut Stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        pub fn sqlite3_bind_text(
+            statement: *mut Stmt,
+            index: c_int,
+            text: *const c_char,
+            len: c_int,
+            destructor: isize,
+        ) -> c_int;
+        pub fn sqlite3_bind_blob(
+            statement: *mut Stmt,
+            index: c_int,
+            blob: *const c_void,
+            len: c_int,
+            destructor: isize,
+        ) -> c_int;
+        pub fn sqlite3_bind_int64(
+            statement: *mut Stmt,
+            index: c_int,
+            value: i64,
+        ) -> c_int;
+        pub fn sqlite3_bind_null(statement: *mut Stmt, index: c_int) -> c_int;
+        pub fn sqlite3_step(statement: *mut Stmt) -> c_int;
+        pub fn sqlite3_reset(statement: *mut Stmt) -> c_int;
+        pub fn sqlite3_finalize(statement: *mut Stmt) -> c_int;
+        pub fn sqlite3_column_type(
+            statement: *mut Stmt,
+            column: c_int,
+        ) -> c_int;
+        pub fn sqlite3_column_blob(
+            statement: *mut Stmt,
+            column: c_int,
+        ) -> *const c_void;
+        pub fn sqlite3_column_text(
+            statement: *mut Stmt,
+            column: c_int,
+        ) -> *const c_uchar;
+        pub fn sqlite3_column_bytes(
+            statement: *mut Stmt,
+            column: c_int,
+        ) -> c_int;
+        pub fn sqlite3_column_int64(statement: *mut Stmt, column: c_int)
+            -> i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn fixture() -> tempfile::TempDir {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("b.md"), b"second").unwrap();
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("nested/c"), b"first").unwrap();
+        root
+    }
+
+    #[test]
+    fn sqlite_store_round_trip() {
+        let root = fixture();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let name = std::ffi::OsStr::from_bytes(b"caf\xe9.txt");
+            fs::write(root.path().join(name), b"latin-1").unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = SqliteStore::new(dir.path().join("index.db"));
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        let first = ResourceId::from_bytes(b"first");
+        index.tag(&first, "twice");
+
+        store.save(&index).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.root(), index.root());
+        assert_eq!(loaded.path2meta, index.path2meta);
+        assert_eq!(loaded.collisions, index.collisions);
+        assert_eq!(loaded.tags(&first), ["twice"]);
+
+        // saving again replaces the rows
+        fs::remove_file(root.path().join("b.md")).unwrap();
+        index.update().unwrap();
+        store.save(&index).unwrap();
+        let loaded = store.load().unwrap();
+        assert_eq!(loaded.path2meta, index.path2meta);
+        assert_eq!(loaded.generation(), 1);
+
+        let db = store.open().unwrap();
+        let mut query = db
+            .prepare(
+                "SELECT count(*) FROM resources JOIN tags USING (id)
+                WHERE extension = 'txt'",
+            )
+            .unwrap();
+        assert!(query.step().unwrap());
+        assert_eq!(query.int(0), 1);
+    }
+
+    #[test]
+    fn older_schemas_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.db");
+        let db = Connection::open(&path).unwrap();
+        db.execute(MIGRATIONS[0]).unwrap();
+        db.execute("PRAGMA user_version = 1").unwrap();
+        drop(db);
+
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let mut store = SqliteStore::new(&path);
+        store.save(&index).unwrap();
+        assert_eq!(store.load().unwrap().path2meta, index.path2meta);
+        let db = store.open().unwrap();
+        assert_eq!(db.user_version().unwrap(), MIGRATIONS.len() as i64);
+
+        db.execute("PRAGMA user_version = 99").unwrap();
+        drop(db);
+        assert!(store.load().is_err());
+    }
+}
@@ -0,0 +1,1235 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use std::time::SystemTime;
+
+use anyhow::Error;
+use canonical_path::{CanonicalPath, CanonicalPathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::encoding::EncodedOsStr;
+use crate::id::ResourceId;
+use crate::index::{
+    portable_relative_path, IndexOptions, IndexRoot, IndexUpdate, ResourceIndex,
+};
+use crate::meta::{ResourceFileType, ResourceMeta};
+
+/// Leading bytes of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Formats an index can be stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexFormat {
+    /// Written by `ResourceIndex::store`.
+    Json,
+    /// Written by `ResourceIndex::store_compressed`.
+    CompressedJson,
+}
+
+impl IndexFormat {
+    /// Recognizes the format by the leading bytes of a stored index.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&ZSTD_MAGIC) {
+            return Some(IndexFormat::CompressedJson);
+        }
+        match header.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => Some(IndexFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where an index is persisted between runs. Implemented by
+/// `FileStore`, `JournalStore` and, with the `sqlite` feature,
+/// `sqlite::SqliteStore`. Other backends can be plugged in by callers.
+pub trait IndexStore {
+    fn save(&mut self, index: &ResourceIndex) -> Result<(), Error>;
+
+    fn load(&self) -> Result<ResourceIndex, Error>;
+}
+
+/// Index stored in a single file, see `ResourceIndex::store`.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileStore { path: path.into() }
+    }
+}
+
+impl IndexStore for FileStore {
+    fn save(&mut self, index: &ResourceIndex) -> Result<(), Error> {
+        index.store(&self.path)
+    }
+
+    fn load(&self) -> Result<ResourceIndex, Error> {
+        ResourceIndex::load(&self.path)
+    }
+}
+
+/// Index stored as a snapshot, written by `ResourceIndex::store`, and
+/// a journal of the paths changed since, in `<path>.journal`. Saving
+/// appends only the entries which differ from the previous save, so it
+/// costs as much as the update rather than the whole index. The first
+/// save of a store made by `new` writes a full snapshot, as does a save
+/// making the journal longer than half of the index.
+#[derive(Debug)]
+pub struct JournalStore {
+    path: PathBuf,
+    journal: PathBuf,
+    /// Entries as of the previous save.
+    saved: Option<HashMap<CanonicalPathBuf, ResourceMeta>>,
+    /// Tags as of the previous save.
+    saved_tags: Tags,
+    journaled: usize,
+}
+
+type Tags = HashMap<ResourceId, HashSet<String>>;
+
+/// Single line of the journal of a `JournalStore`.
+#[derive(Serialize, Deserialize)]
+enum JournalEntry {
+    Put(EncodedOsStr, ResourceMeta),
+    Remove(EncodedOsStr),
+    /// Every tag of the content, none when it was untagged.
+    Tags(ResourceId, Vec<String>),
+}
+
+impl JournalStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let mut journal = path.clone().into_os_string();
+        journal.push(".journal");
+        JournalStore {
+            path,
+            journal: journal.into(),
+            saved: None,
+            saved_tags: Tags::new(),
+            journaled: 0,
+        }
+    }
+
+    /// Loads the index, see `load`, and a store whose next save only
+    /// journals the changes since, e.g. after restarting a process.
+    /// Fails when nothing was saved at `path` yet, see `new`.
+    pub fn open<P: Into<PathBuf>>(
+        path: P,
+    ) -> Result<(Self, ResourceIndex), Error> {
+        let mut store = Self::new(path);
+        let (index, journaled) = store.replay()?;
+        // appending after a broken entry would hide the new ones,
+        // so such a journal is replaced by the next snapshot
+        if let Some(journaled) = journaled {
+            store.saved = Some(index.path2meta.clone());
+            store.saved_tags = index.tag_map().clone();
+            store.journaled = journaled;
+        }
+        Ok((store, index))
+    }
+
+    /// The stored index and the number of journal entries replayed,
+    /// `None` when the journal ends with a broken entry.
+    fn replay(&self) -> Result<(ResourceIndex, Option<usize>), Error> {
+        let mut index = ResourceIndex::load(&self.path)?;
+        let journal = match fs::File::open(&self.journal) {
+            Ok(journal) => BufReader::new(journal),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok((index, Some(0)))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut path2meta = std::mem::take(&mut index.path2meta);
+        let mut tags = index.tag_map().clone();
+        let mut replayed = Some(0);
+        for line in journal.lines() {
+            let entry: JournalEntry = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(msg) => {
+                    log::warn!("Journal ends with a broken entry: {}", msg);
+                    replayed = None;
+                    break;
+                }
+            };
+            let path = match &entry {
+                JournalEntry::Put(path, _) | JournalEntry::Remove(path) => {
+                    OsString::try_from(path.clone())?
+                }
+                JournalEntry::Tags(id, content_tags) => {
+                    tags.remove(id);
+                    if !content_tags.is_empty() {
+                        let content_tags = content_tags.iter().cloned();
+                        tags.insert(id.clone(), content_tags.collect());
+                    }
+                    replayed = replayed.map(|replayed| replayed + 1);
+                    continue;
+                }
+            };
+            // paths were canonical when journaled
+            let path = unsafe { CanonicalPath::from_path_unchecked(&path) }
+                .to_canonical_path_buf();
+            match entry {
+                JournalEntry::Put(_, meta) => {
+                    path2meta.insert(path, meta);
+                }
+                JournalEntry::Remove(_) => {
+                    path2meta.remove(&path);
+                }
+                JournalEntry::Tags(..) => {}
+            }
+            replayed = replayed.map(|replayed| replayed + 1);
+        }
+
+        let mut index = ResourceIndex::assemble(
+            index.roots().to_vec(),
+            index.options().clone(),
+            path2meta,
+        );
+        index.replace_tags(tags);
+        index.mark_clean();
+        Ok((index, replayed))
+    }
+
+    fn snapshot(&mut self, index: &ResourceIndex) -> Result<(), Error> {
+        index.store(&self.path)?;
+        // the journal is obsolete only once the snapshot is complete
+        match fs::remove_file(&self.journal) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                return Err(err.into())
+            }
+            _ => {}
+        }
+        self.saved = Some(index.path2meta.clone());
+        self.saved_tags = index.tag_map().clone();
+        self.journaled = 0;
+        Ok(())
+    }
+}
+
+impl IndexStore for JournalStore {
+    fn save(&mut self, index: &ResourceIndex) -> Result<(), Error> {
+        let saved = match self.saved.as_mut() {
+            Some(saved) => saved,
+            None => return self.snapshot(index),
+        };
+        let removed: Vec<CanonicalPathBuf> = saved
+            .keys()
+            .filter(|path| !index.path2meta.contains_key(*path))
+            .cloned()
+            .collect();
+        let put: Vec<(&CanonicalPathBuf, &ResourceMeta)> = index
+            .path2meta
+            .iter()
+            .filter(|(path, meta)| saved.get(*path) != Some(*meta))
+            .collect();
+        let no_tags = HashSet::new();
+        let tagged = index.tag_map();
+        let mut retagged: Vec<ResourceId> = tagged
+            .keys()
+            .chain(self.saved_tags.keys())
+            .filter(|id| {
+                let now = tagged.get(*id).unwrap_or(&no_tags);
+                now != self.saved_tags.get(*id).unwrap_or(&no_tags)
+            })
+            .cloned()
+            .collect();
+        retagged.sort();
+        retagged.dedup();
+
+        let changes = removed.len() + put.len() + retagged.len();
+        let journaled = self.journaled + changes;
+        if journaled > index.path2meta.len() / 2 {
+            return self.snapshot(index);
+        }
+        log::info!(
+            "Journaling {} changes to {}",
+            changes,
+            self.journal.display()
+        );
+
+        let journal = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal)?;
+        let mut writer = BufWriter::new(journal);
+        for path in removed.iter() {
+            let entry = JournalEntry::Remove(path.as_os_str().into());
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        for (path, meta) in put.iter() {
+            let entry =
+                JournalEntry::Put(path.as_os_str().into(), (*meta).clone());
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        for id in retagged.iter() {
+            let mut content_tags: Vec<String> = tagged
+                .get(id)
+                .map(|tags| tags.iter().cloned().collect())
+                .unwrap_or_default();
+            content_tags.sort();
+            let entry = JournalEntry::Tags(id.clone(), content_tags);
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        for path in removed {
+            saved.remove(&path);
+        }
+        for (path, meta) in put {
+            saved.insert(path.clone(), meta.clone());
+        }
+        for id in retagged {
+            match tagged.get(&id) {
+                Some(tags) => self.saved_tags.insert(id.clone(), tags.clone()),
+                None => self.saved_tags.remove(&id),
+            };
+        }
+        self.journaled = journaled;
+        index.mark_clean();
+        Ok(())
+    }
+
+    /// Loads the snapshot and replays the journal. A journal line cut
+    /// short, e.g. by a crash while saving, ends the replay.
+    fn load(&self) -> Result<ResourceIndex, Error> {
+        self.replay().map(|(index, _)| index)
+    }
+}
+
+fn escape_manifest_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for char in path.chars() {
+        match char {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            char => escaped.push(char),
+        }
+    }
+    escaped
+}
+
+fn unescape_manifest_path(path: &str) -> Result<String, Error> {
+    let mut unescaped = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            unescaped.push(char);
+            continue;
+        }
+        unescaped.push(match chars.next() {
+            Some('\\') => '\\',
+            Some('t') => '\t',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            _ => {
+                return Err(Error::msg(format!(
+                    "Malformed escape in manifest path {}",
+                    path
+                )))
+            }
+        });
+    }
+    Ok(unescaped)
+}
+
+/// Writes a temporary file next to `path`, syncs it and renames it over
+/// `path`, which keeps its previous content if anything fails.
+fn replace_file<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(BufWriter<fs::File>) -> Result<BufWriter<fs::File>, Error>,
+{
+    let name = path.file_name().ok_or_else(|| {
+        Error::msg(format!("{} isn't a file", path.display()))
+    })?;
+    let mut temporary = OsString::from(".");
+    temporary.push(name);
+    temporary.push(".tmp");
+    let temporary = path.with_file_name(temporary);
+
+    let replace = || -> Result<(), Error> {
+        let writer = write(BufWriter::new(fs::File::create(&temporary)?))?;
+        let file = writer
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    };
+    let written = replace();
+    if written.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    written
+}
+
+/// The manifest path `relative` below the canonical `base`. Paths which
+/// could leave `base`, through `..`, absolute or empty components or
+/// symlinks, are refused. Listed files don't have to exist here yet,
+/// the missing part is kept as listed.
+fn path_under(base: &Path, relative: &str) -> Result<CanonicalPathBuf, Error> {
+    let outside =
+        || Error::msg(format!("Manifest path {} leaves the root", relative));
+    let parts: Vec<&str> = relative.split('/').collect();
+    let relative_parts: PathBuf = parts.iter().collect();
+    let normal = relative_parts
+        .components()
+        .all(|part| matches!(part, Component::Normal(_)));
+    if !normal
+        || parts
+            .iter()
+            .any(|part| part.is_empty() || *part == ".")
+    {
+        return Err(outside());
+    }
+
+    let mut resolved = base.to_path_buf();
+    let mut parts = parts.into_iter();
+    for part in parts.by_ref() {
+        let next = resolved.join(part);
+        match fs::canonicalize(&next) {
+            Ok(next) if next.starts_with(base) => resolved = next,
+            Ok(_) => return Err(outside()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                resolved = next;
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    resolved.extend(parts);
+    // resolved as far as it exists, the rest can't be a link
+    Ok(unsafe { CanonicalPath::from_path_unchecked(&resolved) }
+        .to_canonical_path_buf())
+}
+
+impl ResourceIndex {
+    /// Writes the index to `path`, replacing the previous file only
+    /// once the new one is complete, so a crash never leaves a partial
+    /// index behind.
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        log::info!("Storing the index to {}", path.as_ref().display());
+
+        replace_file(path.as_ref(), |mut writer| {
+            self.write_to(&mut writer)?;
+            Ok(writer)
+        })?;
+        self.mark_clean();
+        Ok(())
+    }
+
+    /// Same as `store`, but the serialized index is compressed with zstd.
+    /// `load` recognizes compressed indexes by their leading bytes.
+    /// The previous file is replaced only once the new one is complete.
+    #[cfg(feature = "compression")]
+    pub fn store_compressed<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<(), Error> {
+        log::info!(
+            "Storing the compressed index to {}",
+            path.as_ref().display()
+        );
+
+        replace_file(path.as_ref(), |writer| {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            self.write_to(&mut encoder)?;
+            Ok(encoder.finish()?)
+        })?;
+        self.mark_clean();
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::load_auto(path).map(|(index, _)| index)
+    }
+
+    /// Loads an index stored in any of the formats,
+    /// telling which one it was.
+    pub fn load_auto<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<(Self, IndexFormat), Error> {
+        let path = path.as_ref();
+        log::info!("Loading the index from {}", path.display());
+
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        let format =
+            IndexFormat::detect(reader.fill_buf()?).ok_or_else(|| {
+                Error::msg(format!(
+                    "{} is not an index in any known format",
+                    path.display()
+                ))
+            })?;
+        let index = match format {
+            IndexFormat::Json => Self::read_from(reader)?,
+            IndexFormat::CompressedJson => Self::read_compressed(reader)?,
+        };
+        index.mark_clean();
+        Ok((index, format))
+    }
+
+    /// Loads a stored index and catches up with changes made while it
+    /// wasn't running: `hook` is registered as by `on_update` and sees
+    /// the catch-up update first, then every later one. More hooks can
+    /// be added afterwards without replacing it.
+    ///
+    /// The index is stored back to `path` after each update has been
+    /// delivered, see `checkpoint_to`, so a run interrupted before the
+    /// hooks are done sees the same changes again when resumed, while
+    /// changes delivered completely aren't repeated.
+    pub fn resume<P, F>(path: P, hook: F) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&IndexUpdate) + Send + Sync + 'static,
+    {
+        let mut index = Self::load(&path)?;
+        log::info!("Resuming the index at generation {}", index.generation());
+        index.on_update(hook);
+        index.checkpoint_to(&path);
+        index.update()?;
+        Ok(index)
+    }
+
+    /// Writes only the ids, one per line and sorted, e.g. for
+    /// comparing content of two machines before any transfer.
+    pub fn export_ids<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut ids: Vec<&ResourceId> = self.ids().iter().collect();
+        ids.sort();
+        for id in ids {
+            writeln!(writer, "{}", id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `<relative path>\t<id>` lines sorted by path, paths being
+    /// relative to their roots and separated by `/`, ids formatted as
+    /// `v<version>:<size>-<crc32>` in decimal, followed by
+    /// `-p<prefix length>` for partial ids. Backslashes, tabs and line
+    /// breaks in names are escaped as `\\`, `\t`, `\n` and `\r`, names
+    /// which aren't UTF-8 fail the export. Unchanged content gives the
+    /// same bytes, so manifests can be kept under version control and
+    /// diffed, and other tools can read them with `import_manifest`.
+    pub fn export_manifest<W: Write>(
+        &self,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        let roots = self.canonical_roots();
+        let mut lines: Vec<(String, &ResourceId)> = self
+            .path2meta
+            .iter()
+            .map(|(path, meta)| {
+                if path.to_str().is_none() {
+                    return Err(Error::msg(format!(
+                        "Name of {} isn't UTF-8, it can't be listed",
+                        path.display()
+                    )));
+                }
+                let relative = portable_relative_path(&roots, path.as_path());
+                Ok((escape_manifest_path(&relative), &meta.id))
+            })
+            .collect::<Result<_, Error>>()?;
+        lines.sort();
+        for (path, id) in lines {
+            writeln!(writer, "{}\t{}", path, id)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the paths accepted by `filter`, sorted and relative to
+    /// their roots, for `rsync --files-from` and alike. Names may contain
+    /// newlines, which only null delimiting (`--from0`) can carry, so
+    /// such paths fail the export otherwise.
+    pub fn export_path_list<W: Write>(
+        &self,
+        mut writer: W,
+        null_delimited: bool,
+        filter: Option<&dyn Fn(&ResourceMeta) -> bool>,
+    ) -> Result<(), Error> {
+        let roots = self.canonical_roots();
+        let mut paths: Vec<String> = self
+            .path2meta
+            .iter()
+            .filter(|(_, meta)| filter.is_none_or(|accept| accept(meta)))
+            .map(|(path, _)| portable_relative_path(&roots, path.as_path()))
+            .collect();
+        paths.sort();
+        let delimiter = if null_delimited {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        for path in paths {
+            if path.as_bytes().contains(&delimiter) {
+                return Err(Error::msg(format!(
+                    "Path {:?} can't be listed without null delimiting",
+                    path
+                )));
+            }
+            writer.write_all(path.as_bytes())?;
+            writer.write_all(&[delimiter])?;
+        }
+        Ok(())
+    }
+
+    /// Index of the content listed by a manifest, see `export_manifest`,
+    /// located under `root`. Only paths, ids and sizes are known,
+    /// so the first `update` re-hashes every file still present.
+    pub fn import_manifest<R: BufRead, P: AsRef<Path>>(
+        reader: R,
+        root: P,
+    ) -> Result<Self, Error> {
+        let root = root.as_ref();
+        let base = fs::canonicalize(root).unwrap_or_else(|_| root.into());
+
+        let mut metadata = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let (relative, id) = line.split_once('\t').ok_or_else(|| {
+                Error::msg(format!("Malformed manifest line {}", line))
+            })?;
+            let id: ResourceId = id.parse()?;
+            let path = path_under(&base, &unescape_manifest_path(relative)?)?;
+            let meta = ResourceMeta {
+                size: id.file_size(),
+                modified: SystemTime::UNIX_EPOCH,
+                created: None,
+                file_type: ResourceFileType::File,
+                name: path.file_name().map(Into::into),
+                extension: path.extension().map(Into::into),
+                kind: None,
+                extra: None,
+                inode: None,
+                text: None,
+                minhash: None,
+                id,
+            };
+            metadata.push((path, meta));
+        }
+
+        Ok(Self::assemble(
+            vec![IndexRoot::new(root)],
+            IndexOptions::default(),
+            metadata,
+        ))
+    }
+
+    /// Reads ids written by `export_ids`.
+    pub fn import_ids<R: BufRead>(
+        reader: R,
+    ) -> Result<HashSet<ResourceId>, Error> {
+        let mut ids = HashSet::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.is_empty() {
+                ids.insert(line.parse()?);
+            }
+        }
+        Ok(ids)
+    }
+
+    #[cfg(feature = "compression")]
+    fn read_compressed<R: BufRead>(reader: R) -> Result<Self, Error> {
+        Self::read_from(zstd::Decoder::with_buffer(reader)?)
+    }
+
+    #[cfg(not(feature = "compression"))]
+    fn read_compressed<R: BufRead>(_reader: R) -> Result<Self, Error> {
+        Err(Error::msg(
+            "The index is compressed, `compression` feature is required",
+        ))
+    }
+
+    fn write_to<W: Write>(&self, writer: W) -> Result<(), Error> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    fn read_from<R: Read>(reader: R) -> Result<Self, Error> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, SystemTime};
+
+    use canonical_path::CanonicalPath;
+
+    use crate::id::ResourceId;
+    use crate::meta::{ResourceFileType, ResourceMeta};
+
+    fn synthetic_index(size: usize) -> ResourceIndex {
+        let mut index: ResourceIndex =
+            serde_json::from_str(r#"{"root":"/library","resources":[]}"#)
+                .unwrap();
+        for i in 0..size {
+            let path = format!("/library/album-{}/photo-{}.jpg", i / 100, i);
+            let path = unsafe { CanonicalPath::from_path_unchecked(&path) };
+            let content = (i % (size / 2)).to_string();
+            index.path2meta.insert(
+                path.to_canonical_path_buf(),
+                ResourceMeta {
+                    id: ResourceId::from_bytes(content.as_bytes()),
+                    size: content.len() as u64,
+                    modified: SystemTime::UNIX_EPOCH
+                        + Duration::from_secs(i as u64),
+                    created: None,
+                    file_type: ResourceFileType::File,
+                    name: Some(format!("photo-{}.jpg", i).into()),
+                    extension: Some("jpg".into()),
+                    kind: None,
+                    extra: None,
+                    inode: None,
+                    text: None,
+                    minhash: None,
+                },
+            );
+        }
+        index
+    }
+
+    #[test]
+    fn store_and_load_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let index = synthetic_index(100);
+
+        index.store(&path).unwrap();
+        let loaded = ResourceIndex::load(&path).unwrap();
+
+        assert_eq!(loaded.root(), index.root());
+        assert_eq!(loaded.path2meta, index.path2meta);
+        assert_eq!(loaded.collisions.len(), 50);
+    }
+
+    #[test]
+    fn resume_delivers_catch_up_update() {
+        use std::sync::{Arc, Mutex};
+
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        ResourceIndex::build(root.path())
+            .unwrap()
+            .store(&path)
+            .unwrap();
+
+        fs::write(root.path().join("b.txt"), b"while down").unwrap();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        let mut index = ResourceIndex::resume(&path, move |update| {
+            sink.lock().unwrap().push(update.added.len())
+        })
+        .unwrap();
+        assert_eq!(index.size(), 2);
+
+        fs::write(root.path().join("c.txt"), b"live").unwrap();
+        index.update().unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![1, 1]);
+    }
+
+    #[test]
+    fn interrupted_run_is_resumed() {
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+        use std::sync::{Arc, Mutex};
+
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        ResourceIndex::build(root.path())
+            .unwrap()
+            .store(&path)
+            .unwrap();
+        let names = |update: &IndexUpdate| -> Vec<String> {
+            let mut names: Vec<String> = update
+                .added
+                .keys()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into())
+                .collect();
+            names.sort();
+            names
+        };
+
+        // the first run dies while delivering its second update
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        let mut index = ResourceIndex::resume(&path, move |update| {
+            let names = names(update);
+            assert!(!names.contains(&"crash.txt".to_owned()), "crashed");
+            sink.lock().unwrap().extend(names);
+        })
+        .unwrap();
+        fs::write(root.path().join("b.txt"), b"second").unwrap();
+        index.update().unwrap();
+        fs::write(root.path().join("crash.txt"), b"crash").unwrap();
+        let crashed = catch_unwind(AssertUnwindSafe(|| index.update()));
+        assert!(crashed.is_err());
+        drop(index);
+        assert_eq!(*seen.lock().unwrap(), ["b.txt"]);
+        assert_eq!(ResourceIndex::load(&path).unwrap().generation(), 1);
+
+        // the next run gets only what wasn't delivered completely
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink = seen.clone();
+        let index = ResourceIndex::resume(&path, move |update| {
+            sink.lock().unwrap().extend(names(update))
+        })
+        .unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["crash.txt"]);
+        assert_eq!(index.generation(), 2);
+        assert_eq!(ResourceIndex::load(&path).unwrap().generation(), 2);
+    }
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store: Box<dyn IndexStore> =
+            Box::new(FileStore::new(dir.path().join("index.json")));
+        let index = synthetic_index(10);
+
+        store.save(&index).unwrap();
+        assert_eq!(store.load().unwrap().path2meta, index.path2meta);
+    }
+
+    #[test]
+    fn journal_store_writes_only_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let journal = dir.path().join("index.json.journal");
+        let mut store = JournalStore::new(&path);
+        let mut index = synthetic_index(1000);
+
+        store.save(&index).unwrap();
+        let snapshot = fs::read(&path).unwrap();
+        assert!(!journal.exists());
+
+        let mut paths: Vec<CanonicalPathBuf> =
+            index.path2meta.keys().cloned().collect();
+        paths.sort();
+        index.path2meta.remove(&paths[0]);
+        index.path2meta.get_mut(&paths[1]).unwrap().size += 1;
+        store.save(&index).unwrap();
+        store.save(&index).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), snapshot);
+        let journaled = fs::read_to_string(&journal).unwrap();
+        assert_eq!(journaled.lines().count(), 2);
+        assert!(journaled.len() * 100 < snapshot.len());
+        assert_eq!(store.load().unwrap().path2meta, index.path2meta);
+
+        // a restarted process keeps journaling
+        let (mut store, loaded) = JournalStore::open(&path).unwrap();
+        assert_eq!(loaded.path2meta, index.path2meta);
+        index.path2meta.get_mut(&paths[2]).unwrap().size += 1;
+        store.save(&index).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), snapshot);
+        let journaled = fs::read_to_string(&journal).unwrap();
+        assert_eq!(journaled.lines().count(), 3);
+        assert_eq!(store.load().unwrap().path2meta, index.path2meta);
+
+        for path in paths[2..600].iter() {
+            index.path2meta.remove(path);
+        }
+        store.save(&index).unwrap();
+        assert!(!journal.exists());
+        assert_eq!(store.load().unwrap().path2meta, index.path2meta);
+    }
+
+    #[test]
+    fn journal_store_keeps_tags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let journal = dir.path().join("index.json.journal");
+        let mut store = JournalStore::new(&path);
+        let mut index = synthetic_index(100);
+        let (first, second) =
+            (ResourceId::from_bytes(b"0"), ResourceId::from_bytes(b"1"));
+        index.tag(&first, "kept");
+        store.save(&index).unwrap();
+
+        index.tag(&second, "new");
+        index.replace_tags(
+            index
+                .tag_map()
+                .clone()
+                .into_iter()
+                .filter(|(id, _)| id != &first)
+                .collect(),
+        );
+        store.save(&index).unwrap();
+        assert_eq!(
+            fs::read_to_string(&journal)
+                .unwrap()
+                .lines()
+                .count(),
+            2
+        );
+
+        let (_, loaded) = JournalStore::open(&path).unwrap();
+        assert!(loaded.tags(&first).is_empty());
+        assert_eq!(loaded.tags(&second), vec!["new"]);
+    }
+
+    #[test]
+    fn loaded_index_updates_with_stored_options() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join(".keep"), b"kept").unwrap();
+        fs::write(root.path().join(".other"), b"other").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let options = IndexOptions {
+            hidden_exceptions: HashSet::from([".keep".to_owned()]),
+            ..IndexOptions::default()
+        };
+        ResourceIndex::build_with_options(root.path(), options)
+            .unwrap()
+            .store(&path)
+            .unwrap();
+
+        let mut index = ResourceIndex::load(&path).unwrap();
+        assert!(index
+            .options()
+            .hidden_exceptions
+            .contains(".keep"));
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("nested/.keep"), b"nested").unwrap();
+        let update = index.update().unwrap();
+
+        assert!(update.deleted.is_empty());
+        assert_eq!(update.added.len(), 1);
+        assert_eq!(index.size(), 2);
+    }
+
+    #[test]
+    fn failed_store_keeps_the_previous_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let index = synthetic_index(10);
+        index.store(&path).unwrap();
+        let stored = fs::read(&path).unwrap();
+
+        let failed = replace_file(&path, |mut writer| {
+            writer.write_all(b"{\"root\":")?;
+            Err(Error::msg("interrupted"))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), stored);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn storing_cleans_the_index() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.json");
+        let mut index = ResourceIndex::build(root.path()).unwrap();
+        assert!(index.is_dirty());
+
+        index.store(&path).unwrap();
+        assert!(!index.is_dirty());
+        index.update().unwrap();
+        assert!(!index.is_dirty());
+
+        fs::write(root.path().join("b.txt"), b"second").unwrap();
+        index.update().unwrap();
+        assert!(index.is_dirty());
+        index.store(&path).unwrap();
+
+        let id = ResourceId::from_bytes(b"first");
+        index.tag(&id, "kept");
+        assert!(index.is_dirty());
+        index.store(&path).unwrap();
+        index.tag(&id, "kept");
+        assert!(!index.is_dirty());
+
+        let mut loaded = ResourceIndex::load(&path).unwrap();
+        assert!(!loaded.is_dirty());
+        assert_eq!(loaded.tags(&id), vec!["kept"]);
+        let paths: Vec<CanonicalPathBuf> =
+            loaded.path2meta.keys().cloned().collect();
+        loaded.forget_paths(paths.iter().take(1));
+        assert!(loaded.is_dirty());
+    }
+
+    #[test]
+    fn load_auto_detects_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = synthetic_index(10);
+
+        let json = dir.path().join("index.json");
+        index.store(&json).unwrap();
+        let (loaded, format) = ResourceIndex::load_auto(&json).unwrap();
+        assert_eq!(format, IndexFormat::Json);
+        assert_eq!(loaded.path2meta, index.path2meta);
+
+        #[cfg(feature = "compression")]
+        {
+            let compressed = dir.path().join("index.json.zst");
+            index.store_compressed(&compressed).unwrap();
+            let (loaded, format) =
+                ResourceIndex::load_auto(&compressed).unwrap();
+            assert_eq!(format, IndexFormat::CompressedJson);
+            assert_eq!(loaded.path2meta, index.path2meta);
+        }
+
+        let garbage = dir.path().join("garbage");
+        fs::write(&garbage, b"not an index").unwrap();
+        assert!(ResourceIndex::load_auto(&garbage).is_err());
+    }
+
+    #[test]
+    fn manifest_is_stable() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("nested/b.txt"), b"second").unwrap();
+        let manifest = || {
+            let mut manifest = vec![];
+            ResourceIndex::build(root.path())
+                .unwrap()
+                .export_manifest(&mut manifest)
+                .unwrap();
+            String::from_utf8(manifest).unwrap()
+        };
+
+        let before = manifest();
+        assert_eq!(before, manifest());
+        assert_eq!(
+            before,
+            format!(
+                "a.txt\t{}\nnested/b.txt\t{}\n",
+                ResourceId::from_bytes(b"first"),
+                ResourceId::from_bytes(b"second")
+            )
+        );
+
+        fs::write(root.path().join("a.txt"), b"changed").unwrap();
+        let after = manifest();
+        let changed: Vec<(&str, &str)> = before
+            .lines()
+            .zip(after.lines())
+            .filter(|(old, new)| old != new)
+            .collect();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].1.starts_with("a.txt\t"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn manifest_escapes_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::write(root.path().join("tab\there"), b"tab").unwrap();
+        fs::write(root.path().join("two\nlines\\"), b"lines").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let mut manifest = vec![];
+        index.export_manifest(&mut manifest).unwrap();
+        assert_eq!(
+            String::from_utf8(manifest.clone()).unwrap(),
+            format!(
+                "tab\\there\t{}\ntwo\\nlines\\\\\t{}\n",
+                ResourceId::from_bytes(b"tab"),
+                ResourceId::from_bytes(b"lines")
+            )
+        );
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        let imported =
+            ResourceIndex::import_manifest(&manifest[..], elsewhere.path())
+                .unwrap();
+        let base = fs::canonicalize(elsewhere.path()).unwrap();
+        let mut names: Vec<PathBuf> = imported
+            .path2meta
+            .keys()
+            .map(|path| path.as_path().strip_prefix(&base).unwrap().into())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["tab\there", "two\nlines\\"].map(PathBuf::from));
+
+        fs::write(root.path().join(OsStr::from_bytes(b"raw\xff")), b"raw")
+            .unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        assert!(index.export_manifest(vec![]).is_err());
+    }
+
+    #[test]
+    fn manifest_import_round_trip() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("nested/b.txt"), b"first").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        let mut manifest = vec![];
+        index.export_manifest(&mut manifest).unwrap();
+
+        let elsewhere = tempfile::tempdir().unwrap();
+        let imported =
+            ResourceIndex::import_manifest(&manifest[..], elsewhere.path())
+                .unwrap();
+        assert_eq!(imported.size(), 2);
+        assert_eq!(imported.collisions.len(), 1);
+        assert_eq!(imported.id_at(elsewhere.path()).map(|_| ()), None::<()>);
+        let b = elsewhere
+            .path()
+            .canonicalize()
+            .unwrap()
+            .join("nested")
+            .join("b.txt");
+        let meta = imported
+            .path2meta
+            .iter()
+            .find(|(path, _)| path.as_path() == b)
+            .map(|(_, meta)| meta)
+            .unwrap();
+        assert_eq!(meta.id, ResourceId::from_bytes(b"first"));
+        assert_eq!(meta.size, 5);
+
+        let mut exported = vec![];
+        imported.export_manifest(&mut exported).unwrap();
+        assert_eq!(exported, manifest);
+    }
+
+    #[test]
+    fn path_list_export_round_trip() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir(root.path().join("nested")).unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("nested/with space.txt"), b"second")
+            .unwrap();
+        fs::write(root.path().join("small.txt"), b"s").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let mut list = vec![];
+        let big = |meta: &ResourceMeta| meta.size > 1;
+        index
+            .export_path_list(&mut list, true, Some(&big))
+            .unwrap();
+        let paths: Vec<&[u8]> = list
+            .split(|&byte| byte == 0)
+            .filter(|path| !path.is_empty())
+            .collect();
+        assert_eq!(paths, [&b"a.txt"[..], b"nested/with space.txt"]);
+
+        let mut lines = vec![];
+        index
+            .export_path_list(&mut lines, false, None)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(lines).unwrap(),
+            "a.txt\nnested/with space.txt\nsmall.txt\n"
+        );
+    }
+
+    #[test]
+    fn manifest_paths_stay_under_the_root() {
+        let root = tempfile::tempdir().unwrap();
+        let id = ResourceId::from_bytes(b"first");
+        for path in ["../x", "a/../../x", "/etc/passwd", "a//b", "./a", ""] {
+            let manifest = format!("{}\t{}\n", path, id);
+            let imported = ResourceIndex::import_manifest(
+                manifest.as_bytes(),
+                root.path(),
+            );
+            assert!(imported.is_err(), "{} was imported", path);
+        }
+
+        let manifest = format!("a/b.txt\t{}\n", id);
+        let imported =
+            ResourceIndex::import_manifest(manifest.as_bytes(), root.path())
+                .unwrap();
+        let base = fs::canonicalize(root.path()).unwrap();
+        let path = imported.path2meta.keys().next().unwrap();
+        assert_eq!(path.as_path(), base.join("a/b.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn manifest_paths_dont_follow_links_out() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("out"))
+            .unwrap();
+        let manifest = format!("out/x\t{}\n", ResourceId::from_bytes(b"x"));
+        assert!(ResourceIndex::import_manifest(
+            manifest.as_bytes(),
+            root.path()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn ids_export_round_trip() {
+        let json = serde_json::to_string(&synthetic_index(100)).unwrap();
+        let index: ResourceIndex = serde_json::from_str(&json).unwrap();
+
+        let mut exported = vec![];
+        index.export_ids(&mut exported).unwrap();
+        let imported = ResourceIndex::import_ids(&exported[..]).unwrap();
+
+        assert_eq!(exported.iter().filter(|&&b| b == b'\n').count(), 50);
+        assert_eq!(&imported, index.ids());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = dir.path().join("index.json");
+        let compressed = dir.path().join("index.json.zst");
+        let index = synthetic_index(50_000);
+
+        index.store(&plain).unwrap();
+        index.store_compressed(&compressed).unwrap();
+        let loaded = ResourceIndex::load(&compressed).unwrap();
+
+        assert_eq!(loaded.path2meta, index.path2meta);
+        assert!(
+            fs::metadata(&compressed).unwrap().len() * 4
+                < fs::metadata(&plain).unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Component, PathBuf};
+
+use crate::index::{common_ancestor, ResourceIndex};
+use crate::meta::ResourceMeta;
+
+/// Indexed files arranged by directories, see `ResourceIndex::to_tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeNode {
+    /// Entries sorted by name.
+    Directory(BTreeMap<OsString, TreeNode>),
+    File(ResourceMeta),
+}
+
+impl TreeNode {
+    /// The node at the relative path, if any.
+    pub fn get<P: Into<PathBuf>>(&self, path: P) -> Option<&TreeNode> {
+        path.into()
+            .components()
+            .try_fold(self, |node, component| match node {
+                TreeNode::Directory(entries) => {
+                    entries.get(component.as_os_str())
+                }
+                TreeNode::File(_) => None,
+            })
+    }
+}
+
+impl ResourceIndex {
+    /// The indexed files as a tree, together with the directory it
+    /// starts at: the root, or the closest common ancestor of several
+    /// roots. Directories without indexed files are left out.
+    pub fn to_tree(&self) -> (PathBuf, TreeNode) {
+        let roots = self.canonical_roots();
+        let base = common_ancestor(roots.iter().map(|root| root.as_path()));
+
+        let mut tree = TreeNode::Directory(BTreeMap::new());
+        for (path, meta) in self.path2meta.iter() {
+            let relative = match path.as_path().strip_prefix(&base) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            let names: Vec<OsString> = relative
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_owned()),
+                    _ => None,
+                })
+                .collect();
+            let (file, dirs) = match names.split_last() {
+                Some(split) => split,
+                None => continue,
+            };
+
+            let mut node = &mut tree;
+            for dir in dirs {
+                node = match node {
+                    TreeNode::Directory(entries) => {
+                        entries.entry(dir.clone()).or_insert_with(|| {
+                            TreeNode::Directory(BTreeMap::new())
+                        })
+                    }
+                    TreeNode::File(_) => unreachable!("files have no children"),
+                };
+            }
+            if let TreeNode::Directory(entries) = node {
+                entries.insert(file.clone(), TreeNode::File(meta.clone()));
+            }
+        }
+        (base, tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::id::ResourceId;
+
+    #[test]
+    fn tree_follows_directory_layout() {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        fs::create_dir_all(root.path().join("photos/2022")).unwrap();
+        fs::create_dir(root.path().join("empty")).unwrap();
+        fs::write(root.path().join("a.txt"), b"first").unwrap();
+        fs::write(root.path().join("photos/2022/cat.jpg"), b"cat").unwrap();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let (base, tree) = index.to_tree();
+        assert_eq!(base, root.path().canonicalize().unwrap());
+
+        let names = |node: &TreeNode| match node {
+            TreeNode::Directory(entries) => entries.keys().cloned().collect(),
+            TreeNode::File(_) => vec![],
+        };
+        assert_eq!(names(&tree), ["a.txt", "photos"]);
+        assert_eq!(names(tree.get("photos").unwrap()), ["2022"]);
+        match tree.get("photos/2022/cat.jpg") {
+            Some(TreeNode::File(meta)) => {
+                assert_eq!(meta.id, ResourceId::from_bytes(b"cat"))
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(tree.get("empty").is_none());
+    }
+}
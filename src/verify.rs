@@ -0,0 +1,252 @@
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use anyhow::Error;
+use canonical_path::CanonicalPathBuf;
+
+use crate::id::ResourceId;
+use crate::index::{EmptyHandling, ResourceIndex, SymlinkMode};
+use crate::meta::{link_target, ResourceFileType};
+
+/// Outcome of re-hashing the indexed files.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of paths checked before finishing or being cancelled.
+    pub checked: usize,
+    pub total: usize,
+    /// Paths whose content doesn't match the indexed id.
+    pub mismatched: Vec<CanonicalPathBuf>,
+    pub missing: Vec<CanonicalPathBuf>,
+    pub unreadable: Vec<CanonicalPathBuf>,
+    /// The report covers only the first `checked` paths.
+    pub cancelled: bool,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        !self.cancelled
+            && self.mismatched.is_empty()
+            && self.missing.is_empty()
+            && self.unreadable.is_empty()
+    }
+}
+
+impl ResourceIndex {
+    /// Re-hashes every indexed file and compares it with the stored id.
+    /// Expensive, all the content is read again.
+    pub fn verify(&self) -> Result<VerifyReport, Error> {
+        self.verify_with(Arc::new(AtomicBool::new(false)), |_, _| {})
+    }
+
+    /// Same as `verify`, but reports `(checked, total)` after each file
+    /// and stops early with a partial report once `cancel` is set.
+    pub fn verify_with<F: FnMut(usize, usize)>(
+        &self,
+        cancel: Arc<AtomicBool>,
+        mut on_progress: F,
+    ) -> Result<VerifyReport, Error> {
+        log::info!("Verifying the index");
+
+        let mut paths: Vec<&CanonicalPathBuf> = self.path2meta.keys().collect();
+        paths.sort();
+
+        let mut report = VerifyReport {
+            total: paths.len(),
+            ..VerifyReport::default()
+        };
+
+        for path in paths {
+            if cancel.load(Ordering::Relaxed) {
+                log::info!("Verification cancelled");
+                report.cancelled = true;
+                break;
+            }
+
+            match self.rehash(path) {
+                Ok(id) => {
+                    if id != self.path2meta[path].id {
+                        log::warn!("Content of {} changed", path.display());
+                        report.mismatched.push(path.clone());
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    report.missing.push(path.clone());
+                }
+                Err(err) => {
+                    log::error!("Couldn't read {}: {}", path.display(), err);
+                    report.unreadable.push(path.clone());
+                }
+            }
+
+            report.checked += 1;
+            on_progress(report.checked, report.total);
+        }
+
+        Ok(report)
+    }
+
+    /// Id of the file as it is now, computed the way scanning does.
+    fn rehash(&self, path: &CanonicalPathBuf) -> io::Result<ResourceId> {
+        let options = self.options();
+        // such links are indexed by their target path
+        let link = self.path2meta[path].file_type == ResourceFileType::Symlink
+            && options.symlinks == SymlinkMode::HashLinkTarget;
+        if link {
+            return Ok(ResourceId::from_bytes(&link_target(path.as_path())?));
+        }
+        let file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        let distinct_empty =
+            options.empty_file_handling == EmptyHandling::DistinctById;
+        match options.partial_hash {
+            _ if size == 0 && distinct_empty => {
+                Ok(ResourceId::of_empty_at(path.as_path()))
+            }
+            Some(prefix_len) => {
+                ResourceId::partial_from_reader(size, file, prefix_len)
+            }
+            None => ResourceId::from_reader(file),
+        }
+    }
+
+    /// Paths whose size or modification time differ from the index,
+    /// i.e. which need re-hashing, sorted. Nothing is read but the
+    /// metadata, so it's cheap enough to run often and `verify` only
+    /// the flagged paths. Missing files are left to `missing_resources`.
+    pub fn quick_verify(&self) -> Vec<CanonicalPathBuf> {
+        let mut drifted: Vec<CanonicalPathBuf> = self
+            .path2meta
+            .iter()
+            // indexed links are either resolved or stand for themselves
+            .filter(|(path, meta)| match fs::symlink_metadata(path) {
+                Ok(metadata) => {
+                    metadata.len() != meta.size
+                        || metadata.modified().ok() != Some(meta.modified)
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+                Err(err) => {
+                    log::error!("Couldn't stat {}: {}", path.display(), err);
+                    true
+                }
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        drifted.sort();
+        drifted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::IndexOptions;
+
+    fn fixture() -> tempfile::TempDir {
+        let root = tempfile::Builder::new()
+            .prefix("arklib")
+            .tempdir()
+            .unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(root.path().join(name), name).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn verify_detects_changes() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        assert!(index.verify().unwrap().is_intact());
+
+        fs::write(root.path().join("a.txt"), "A.txt").unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+
+        let report = index.verify().unwrap();
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.mismatched.len(), 1);
+        assert_eq!(report.missing.len(), 1);
+    }
+
+    #[test]
+    fn partial_index_is_intact() {
+        let root = fixture();
+        fs::write(root.path().join("big.bin"), vec![7u8; 5000]).unwrap();
+        let index = ResourceIndex::build_partial(root.path(), 100).unwrap();
+        assert!(index.verify().unwrap().is_intact());
+
+        fs::write(root.path().join("big.bin"), vec![8u8; 5000]).unwrap();
+        assert_eq!(index.verify().unwrap().mismatched.len(), 1);
+    }
+
+    #[test]
+    fn distinct_empty_files_are_intact() {
+        let root = fixture();
+        fs::write(root.path().join("empty.txt"), b"").unwrap();
+        fs::write(root.path().join("void.txt"), b"").unwrap();
+        let options = IndexOptions {
+            empty_file_handling: EmptyHandling::DistinctById,
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert_eq!(index.size(), 5);
+        assert!(index.verify().unwrap().is_intact());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hashed_links_are_intact() {
+        let root = fixture();
+        let link = root.path().join("link.txt");
+        std::os::unix::fs::symlink(root.path().join("a.txt"), &link).unwrap();
+        let options = IndexOptions {
+            symlinks: SymlinkMode::HashLinkTarget,
+            ..IndexOptions::default()
+        };
+        let index =
+            ResourceIndex::build_with_options(root.path(), options).unwrap();
+        assert_eq!(index.size(), 4);
+        assert!(index.verify().unwrap().is_intact());
+
+        fs::remove_file(&link).unwrap();
+        std::os::unix::fs::symlink(root.path().join("b.txt"), &link).unwrap();
+        assert_eq!(index.verify().unwrap().mismatched.len(), 1);
+    }
+
+    #[test]
+    fn cancelled_verify_is_partial() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut progress = vec![];
+        let report = index
+            .verify_with(cancel.clone(), |checked, total| {
+                progress.push((checked, total));
+                cancel.store(true, Ordering::Relaxed);
+            })
+            .unwrap();
+
+        assert!(report.cancelled);
+        assert!(!report.is_intact());
+        assert_eq!(report.checked, 1);
+        assert_eq!(report.total, 3);
+        assert_eq!(progress, [(1, 3)]);
+    }
+
+    #[test]
+    fn quick_verify_flags_drifted_stats() {
+        let root = fixture();
+        let index = ResourceIndex::build(root.path()).unwrap();
+        assert!(index.quick_verify().is_empty());
+
+        fs::write(root.path().join("a.txt"), "longer a.txt").unwrap();
+        fs::remove_file(root.path().join("b.txt")).unwrap();
+
+        let drifted = index.quick_verify();
+        assert_eq!(drifted.len(), 1);
+        assert!(drifted[0].ends_with("a.txt"));
+    }
+}